@@ -0,0 +1,121 @@
+use std::hint::black_box;
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use polars::prelude::*;
+use locreadion::{CoverageMetric, JoinStrategy, Options, calc_coverage, cigar_parser, disambiguate_reads, join_overlaps, merge_range};
+
+// a long-read transcript of `exons` exons, the kind of CIGAR a minimap2 -ax splice run writes;
+// six operations per exon
+fn spliced_cigar(exons: usize) -> String {
+    let mut cigar = String::from("12S");
+    for i in 0..exons {
+        if i > 0 {
+            cigar.push_str(&format!("{}N", 300 + 17 * i));
+        }
+        cigar.push_str(&format!("{}M1I{}M2D{}M", 40 + i, 30, 25));
+    }
+    cigar.push_str("8S");
+    cigar
+}
+
+fn bench_cigar_parser(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cigar_parser");
+    let spliced = spliced_cigar(40);
+    // 1667 exons is 10,003 operations, a pathological long read; valid_ranges is still sized once up front
+    let huge = spliced_cigar(1667);
+    for (name, cigar) in [
+        ("short exonic", "101M"), ("clipped short read", "5S90M2I4M"), ("spliced short read", "20M100N30M"),
+        ("long spliced", spliced.as_str()), ("10k operations", huge.as_str()),
+    ] {
+        group.bench_with_input(BenchmarkId::from_parameter(name), cigar, |b, cigar| {
+            b.iter(|| cigar_parser(black_box(cigar), black_box(10_000)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_merge_range(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merge_range");
+    // already apart, nothing merges
+    let disjoint: Vec<(i64, i64)> = (0..1000).map(|i| (i * 100, i * 100 + 50)).collect();
+    // every interval overlaps the next, everything merges into one
+    let overlapping: Vec<(i64, i64)> = (0..1000).map(|i| (i * 100, i * 100 + 150)).collect();
+    // every interval touches the next, everything merges into one as well
+    let touching: Vec<(i64, i64)> = (0..1000).map(|i| (i * 100, i * 100 + 100)).collect();
+    for (name, intervals) in [("disjoint", &disjoint), ("overlapping", &overlapping), ("touching", &touching)] {
+        group.bench_with_input(BenchmarkId::from_parameter(name), intervals, |b, intervals| {
+            b.iter(|| merge_range(black_box(intervals)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_calc_coverage(c: &mut Criterion) {
+    let mut group = c.benchmark_group("calc_coverage");
+    let spliced = spliced_cigar(40);
+    let spliced_end = cigar_parser(&spliced, 10_000).unwrap().last().unwrap().1;
+    let cases = [
+        ("short exonic", 10_000, 10_101, "101M"),
+        ("long spliced", 10_000, spliced_end, spliced.as_str()),
+    ];
+    for (name, start, end, cigar) in cases {
+        for metric in [CoverageMetric::Total, CoverageMetric::MaxBlock] {
+            let id = format!("{} {}", name, if metric == CoverageMetric::Total { "total" } else { "max-block" });
+            group.bench_function(id, |b| {
+                b.iter(|| calc_coverage(black_box(start), black_box(end), black_box(start + 50), black_box(end - 50), black_box(cigar), metric))
+            });
+        }
+    }
+    group.finish();
+}
+
+// `reads` reads of 101 bases, each overlapping a region of both BED files, laid out like the
+// intersect output disambiguate_reads expects; region A covers more of every other read
+fn synthetic_overlaps(reads: usize) -> PolarsResult<(DataFrame, Vec<DataFrame>)> {
+    let names: Vec<String> = (0..reads).map(|i| format!("read{}", i)).collect();
+    let starts: Vec<i64> = (0..reads as i64).map(|i| i * 37).collect();
+    let ends: Vec<i64> = starts.iter().map(|start| start + 101).collect();
+    let bam_df = df!(
+        "column_1" => &names,
+        "column_3" => vec!["chr1"; reads],
+        "column_4" => starts.iter().map(|start| start + 1).collect::<Vec<_>>(),
+        "column_6" => vec!["101M"; reads],
+    )?;
+    let bed_df = |name: &str, shift: i64| df!(
+        "column_1" => vec!["chr1"; reads],
+        "column_2" => &starts,
+        "column_3" => &ends,
+        "column_4" => &names,
+        "column_14" => starts.iter().enumerate().map(|(i, start)| start + shift * (i as i64 % 2)).collect::<Vec<_>>(),
+        "column_15" => ends.iter().map(|end| end + 200).collect::<Vec<_>>(),
+        "column_16" => (0..reads).map(|i| format!("{}{}", name, i / 10)).collect::<Vec<_>>(),
+    );
+    Ok((bam_df, vec![bed_df("A", 10)?, bed_df("B", 40)?]))
+}
+
+fn bench_disambiguate_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("disambiguate_reads");
+    group.sample_size(10);
+    for reads in [10_000, 100_000] {
+        let (bam_df, bed_dfs) = synthetic_overlaps(reads).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(reads), &reads, |b, _| {
+            b.iter(|| disambiguate_reads(bam_df.clone(), bed_dfs.clone(), &Options::default()).unwrap())
+        });
+    }
+    group.finish();
+}
+
+// the BED-BAM join alone under each --join-strategy, on one BED file of the 100k-read frames
+fn bench_join_strategy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("join_overlaps");
+    group.sample_size(10);
+    let (bam_df, bed_dfs) = synthetic_overlaps(100_000).unwrap();
+    for (name, join_strategy) in [("auto", JoinStrategy::Auto), ("serial", JoinStrategy::Serial), ("parallel", JoinStrategy::Parallel)] {
+        group.bench_function(name, |b| {
+            b.iter(|| join_overlaps(bed_dfs[0].clone(), bam_df.clone(), join_strategy).collect().unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_cigar_parser, bench_merge_range, bench_calc_coverage, bench_disambiguate_reads, bench_join_strategy);
+criterion_main!(benches);
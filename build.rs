@@ -0,0 +1,21 @@
+use std::process::Command;
+
+// the commit a binary was built from, for `readloc --version`; builds outside a git
+// checkout, e.g. from a crates.io tarball, report none
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=READLOC_GIT_COMMIT={}", commit);
+    // HEAD names the branch, the branch ref moves with every commit
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Ok(head) = std::fs::read_to_string(".git/HEAD") {
+        if let Some(branch) = head.trim().strip_prefix("ref: ") {
+            println!("cargo:rerun-if-changed=.git/{}", branch);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::path::Path;
+use clap::ValueEnum;
+use crate::Region;
+
+/// Features of an annotation given to -r that become regions.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FeatureType {
+    /// Gene records, named by gene_id (GTF) or ID (GFF3)
+    Gene,
+    /// Transcript or mRNA records, named by transcript_id (GTF) or ID (GFF3)
+    Transcript,
+    /// Exon records, named by exon_id, else transcript_id (GTF), or by ID, else Parent (GFF3)
+    Exon,
+}
+
+impl FeatureType {
+    // values of the feature column taken for this type
+    pub fn gff_types(self) -> &'static [&'static str] {
+        match self {
+            FeatureType::Gene => &["gene"],
+            FeatureType::Transcript => &["transcript", "mRNA"],
+            FeatureType::Exon => &["exon"],
+        }
+    }
+
+    fn gtf_keys(self) -> &'static [&'static str] {
+        match self {
+            FeatureType::Gene => &["gene_id"],
+            FeatureType::Transcript => &["transcript_id"],
+            FeatureType::Exon => &["exon_id", "transcript_id"],
+        }
+    }
+}
+
+pub fn is_annotation(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    name.ends_with(".gtf") || name.ends_with(".gff3") || name.ends_with(".gff")
+}
+
+// GTF attributes are `key "value";` pairs, GFF3 attributes `key=value` pairs separated by `;`
+fn attribute<'a>(attributes: &'a str, key: &str, gtf: bool) -> Option<&'a str> {
+    attributes.split(';')
+        .map(str::trim)
+        .find_map(|pair| {
+            let (k, v) = if gtf { pair.split_once(' ')? } else { pair.split_once('=')? };
+            (k == key).then(|| v.trim().trim_matches('"'))
+        })
+}
+
+// annotations are 1-based and closed, the regions are 0-based and half-open like BED;
+// features are kept in file order, a name shared by several records gives several intervals;
+// the error names the first malformed line
+pub fn read_annotation(content: &str, path: &Path, feature_type: FeatureType) -> Result<Vec<Region>, String> {
+    let name = path.to_string_lossy();
+    let gtf = name.strip_suffix(".gz").unwrap_or(&name).ends_with(".gtf");
+    let keys: &[&str] = if gtf { feature_type.gtf_keys() } else { &["ID", "Parent"] };
+    let mut regions = vec![];
+    for (i, line) in content.lines().enumerate() {
+        // GFF3 ends with an optional FASTA section
+        if line.starts_with("##FASTA") {
+            break;
+        }
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 9 {
+            return Err(format!("line {}, expected 9 tab-separated columns", i + 1));
+        }
+        if !feature_type.gff_types().contains(&fields[2]) {
+            continue;
+        }
+        let (Ok(start), Ok(end)) = (fields[3].parse::<i64>(), fields[4].parse::<i64>()) else {
+            return Err(format!("line {}, invalid coordinates", i + 1));
+        };
+        let Some(region_name) = keys.iter().find_map(|key| attribute(fields[8], key, gtf)) else {
+            return Err(format!("line {}, none of the attributes {}", i + 1, keys.join(", ")));
+        };
+        regions.push(Region {
+            chr: fields[0].to_string(),
+            start: start - 1,
+            end,
+            name: region_name.to_string(),
+            strand: fields[6].to_string(),
+        });
+    }
+    Ok(regions)
+}
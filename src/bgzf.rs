@@ -0,0 +1,87 @@
+use std::io::{self, Write};
+use flate2::{Compression, Crc};
+use flate2::write::DeflateEncoder;
+
+// BGZF is a series of gzip members of at most 64 KiB, each carrying its compressed size in a `BC`
+// extra field so tabix can seek to any block; plain gzip readers see the concatenated stream
+const BLOCK_DATA: usize = 0xff00;
+// the empty block samtools and bgzip end every file with
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, b'B', b'C', 0x02, 0,
+    0x1b, 0, 0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> BgzfWriter<W> {
+        BgzfWriter { inner, buffer: Vec::with_capacity(BLOCK_DATA) }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.buffer)?;
+        let compressed = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(&self.buffer);
+        // 18 header bytes, the deflate data, then CRC32 and ISIZE; a full block of
+        // incompressible data is stored with a few bytes of overhead and still fits 16 bits
+        let block_size = (18 + compressed.len() + 8 - 1) as u16;
+        self.inner.write_all(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, b'B', b'C', 0x02, 0])?;
+        self.inner.write_all(&block_size.to_le_bytes())?;
+        self.inner.write_all(&compressed)?;
+        self.inner.write_all(&crc.sum().to_le_bytes())?;
+        self.inner.write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.buffer.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.write_all(&EOF_BLOCK)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(BLOCK_DATA - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == BLOCK_DATA {
+            self.write_block()?;
+        }
+        Ok(n)
+    }
+
+    // blocks are only cut when full, so frequent flushes do not shrink them
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use flate2::read::MultiGzDecoder;
+    use super::{BgzfWriter, EOF_BLOCK};
+
+    #[test]
+    fn blocks_decompress_to_the_input() {
+        // three blocks, the last one partial
+        let input: Vec<u8> = (0..20000).flat_map(|i| format!("chr1\t{}\t{}\tr{}\n", i, i + 50, i).into_bytes()).collect();
+        assert!(input.len() > 2 * 0xff00);
+        let mut writer = BgzfWriter::new(vec![]);
+        writer.write_all(&input).unwrap();
+        let compressed = writer.finish().unwrap();
+        assert!(compressed.ends_with(&EOF_BLOCK));
+        let mut decompressed = vec![];
+        MultiGzDecoder::new(&compressed[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, input);
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tempfile::TempDir;
+
+// bumped whenever the layout of the stored intersect output changes
+const CACHE_VERSION: u32 = 1;
+
+// the intersect output of one alignment and BED file under --cache-dir, a directory named after the
+// BED file and a hash of the inputs; the full key is stored next to the output and compared on load,
+// so a hash collision or a different hasher is only a miss
+pub struct CacheEntry {
+    dir: PathBuf,
+    key: String,
+}
+
+// size and modification time, a rewritten file gets a new key even when its path is reused
+fn stamp(path: &Path) -> Option<String> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!("{}\t{}\t{}.{:09}", path.to_string_lossy(), metadata.len(), mtime.as_secs(), mtime.subsec_nanos()))
+}
+
+impl CacheEntry {
+    // None when an input cannot be stamped, the intersect then runs uncached
+    pub fn new(cache_dir: &Path, align_file: &Path, region_path: &Path, strand_flag: Option<&str>, split: bool, bedtools: &Path) -> Option<CacheEntry> {
+        let key = format!(
+            "locreadion intersect cache {}\nalignment\t{}\nregions\t{}\nstrand\t{}\nsplit\t{}\nbedtools\t{}\n",
+            CACHE_VERSION,
+            stamp(align_file)?,
+            stamp(region_path)?,
+            strand_flag.unwrap_or("none"),
+            split,
+            bedtools.to_string_lossy(),
+        );
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let file_name = region_path.file_name()?.to_string_lossy();
+        let dir = cache_dir.join(format!("{}-{:016x}", file_name, hasher.finish()));
+        Some(CacheEntry { dir, key })
+    }
+
+    // the stored `-ubam` and `-bed` outputs when the key matches
+    pub fn load(&self) -> Option<(PathBuf, PathBuf)> {
+        let stored = fs::read_to_string(self.dir.join("key")).ok()?;
+        let (sam, bed) = (self.dir.join("intersect.sam"), self.dir.join("intersect.bed"));
+        (stored == self.key && sam.is_file() && bed.is_file()).then_some((sam, bed))
+    }
+
+    // where a miss writes its output, next to the entry so the final rename stays on one file system
+    pub fn staging(&self) -> std::io::Result<TempDir> {
+        tempfile::Builder::new()
+            .prefix(".staging-")
+            .tempdir_in(self.dir.parent().unwrap())
+    }
+
+    // the key is written last and the directory renamed into place, so an interrupted run never leaves
+    // an entry that loads; when another run stored the same entry first, its copy is kept
+    pub fn store(&self, staging: TempDir) -> std::io::Result<(PathBuf, PathBuf)> {
+        fs::write(staging.path().join("key"), &self.key)?;
+        if self.dir.is_dir() && self.load().is_none() {
+            fs::remove_dir_all(&self.dir)?;
+        }
+        match fs::rename(staging.path(), &self.dir) {
+            // the directory moved, there is nothing left for the guard to remove
+            Ok(()) => {
+                let _ = staging.into_path();
+            }
+            Err(e) if self.load().is_none() => return Err(e),
+            Err(_) => {}
+        }
+        Ok((self.dir.join("intersect.sam"), self.dir.join("intersect.bed")))
+    }
+}
@@ -0,0 +1,64 @@
+use std::path::PathBuf;
+use polars::prelude::PolarsError;
+use thiserror::Error;
+
+// every variant but Usage names the file or BED being processed, so a failure in a large batch can be traced back;
+// all of them reach main, so the temporary directories of a run are removed on the way
+#[derive(Debug, Error)]
+pub enum ReadlocError {
+    #[error("failed to {op} {}: {source}", path.display())]
+    Io {
+        op: &'static str,
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{cmd} failed while screening {bed}: {source}")]
+    Command {
+        cmd: &'static str,
+        bed: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("failed to parse the {what} intersect output of {bed}: {source}")]
+    Parse {
+        what: &'static str,
+        bed: String,
+        #[source]
+        source: PolarsError,
+    },
+    #[error("column {column} is missing from the {what} intersect output of {bed}")]
+    MissingColumn {
+        column: &'static str,
+        what: &'static str,
+        bed: String,
+    },
+    #[error("failed to {op} {bed}: {reason}")]
+    Bed {
+        op: &'static str,
+        bed: String,
+        reason: String,
+    },
+    #[error("invalid {what} {}: {reason}", path.display())]
+    Invalid {
+        what: &'static str,
+        path: PathBuf,
+        reason: String,
+    },
+    // exits with EXIT_TIMEOUT rather than 1, after the .partial outputs of what finished are written
+    #[error("{} reached the time limit of {limit}s, {}", region_dir.display(), if *partial { "the outputs are partial" } else { "no BED file finished" })]
+    TimedOut {
+        region_dir: PathBuf,
+        limit: u64,
+        partial: bool,
+    },
+    // options that cannot be combined or are missing, reported as given
+    #[error("{0}")]
+    Usage(String),
+    #[error(transparent)]
+    Polars(#[from] PolarsError),
+    #[error(transparent)]
+    OtherIo(#[from] std::io::Error),
+}
+
+pub type ReadlocResult<T> = Result<T, ReadlocError>;
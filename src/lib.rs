@@ -0,0 +1,1422 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use polars::lazy::dsl::GetOutput;
+use polars::prelude::*;
+use clap::ValueEnum;
+use rayon::prelude::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CoverageMetric {
+    /// Sum of the overlaps of all aligned blocks
+    Total,
+    /// Largest overlap of a single contiguous aligned block
+    MaxBlock,
+}
+
+/// Which region an ambiguous read goes to when several have its best coverage.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TieBreak {
+    /// Keep the first candidate in intersect order (the previous behaviour)
+    First,
+    /// Keep the lexicographically smallest region name
+    RegionName,
+    /// Keep the shortest region
+    ShortestRegion,
+    /// Leave reads with tied candidates unassigned
+    Drop,
+}
+
+/// How the two sides of the BED-BAM join are evaluated. polars always hash-joins on the
+/// multi-column (read, chr) string key, so only their parallelism can be tuned.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum JoinStrategy {
+    /// Let polars decide whether to evaluate both sides in parallel
+    Auto,
+    /// Evaluate both sides of the join one after another
+    Serial,
+    /// Always evaluate both sides of the join in parallel
+    Parallel,
+}
+
+/// Convention of the start coordinates written out. Ends are the same under both, the
+/// last base of a 1-based closed interval is the exclusive end of the 0-based one.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CoordBase {
+    /// 0-based half-open like BED and bedtools
+    #[value(name = "0")]
+    Zero,
+    /// 1-based closed like SAM, GTF and most genome browsers
+    #[value(name = "1")]
+    One,
+}
+
+/// Settings of [`disambiguate_reads`] and [`Disambiguation`], the defaults match the CLI defaults.
+///
+/// A candidate is scored by its plain coverage under `metric`, or by one of [`qual_coverage`],
+/// [`track_coverage`] and [`three_prime_coverage`] when `qual_weight`, `track` or `three_prime`
+/// is set (at most one of them), then multiplied by its `prior` and divided by the region
+/// length under `fraction`.
+#[derive(Clone)]
+pub struct Options {
+    pub metric: CoverageMetric,
+    pub tie_break: TieBreak,
+    pub join_strategy: JoinStrategy,
+    /// Reads whose best coverage is below this many bases are dropped
+    pub min_coverage: Option<i64>,
+    /// Divide every score by the length of its region, so the region the read fills best wins
+    pub fraction: bool,
+    /// Weight every covered base by its quality, from the `qual` column
+    pub qual_weight: bool,
+    /// Weight every covered base by its value in the track
+    pub track: Option<WeightTrack>,
+    /// Only count the last this many aligned bases at the read's 3' end, the strand comes from
+    /// the `flag` column; candidates whose window misses the region are dropped
+    pub three_prime: Option<i64>,
+    /// Weight of the candidates of each region, 1 for regions missing from it
+    pub prior: HashMap<String, f64>,
+    /// Multiply every score by the `weight` column, the confidence of the candidate's alignment
+    pub alignment_weight: bool,
+    /// BED file names without extension, see [`prefer_sources`]; empty to leave ties to `tie_break`
+    pub feature_priority: Vec<String>,
+    /// Candidates covering fewer bases of their region, or less than this fraction of it, are dropped
+    pub min_absolute_and_fraction: Option<(i64, f64)>,
+    /// Alignments with fewer aligned reference bases, see [`aligned_span`], are dropped
+    pub min_read_length: Option<i64>,
+    /// Send the mates of a fragment, rows sharing `read` told apart by `read_name` and `flag`,
+    /// to the region of their highest summed score
+    pub paired: bool,
+    /// Keep the best this many candidates of every read with their `rank` instead of one
+    pub top_k: Option<u32>,
+    /// Keep every scorable candidate of the ambiguous reads instead of the best one
+    pub no_resolve: bool,
+    /// Called once for every ambiguous read the arg_max picks a region for, e.g. by a progress bar
+    pub on_resolved: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            metric: CoverageMetric::Total,
+            tie_break: TieBreak::First,
+            join_strategy: JoinStrategy::Auto,
+            min_coverage: None,
+            fraction: false,
+            qual_weight: false,
+            track: None,
+            three_prime: None,
+            prior: HashMap::new(),
+            alignment_weight: false,
+            feature_priority: vec![],
+            min_absolute_and_fraction: None,
+            min_read_length: None,
+            paired: false,
+            top_k: None,
+            no_resolve: false,
+            on_resolved: None,
+        }
+    }
+}
+
+/// Merges overlapping or touching intervals, which must be sorted by start. The intervals are
+/// half-open, so `[100, 200)` and `[200, 300)` touch and cover `[100, 300)` without a gap,
+/// while a gap of a single base keeps them apart:
+///
+/// ```
+/// use locreadion::merge_range;
+/// let mut exons = vec![(500, 600), (100, 250), (200, 300), (300, 350)];
+/// exons.sort_unstable();
+/// assert_eq!(merge_range(&exons), vec![(100, 350), (500, 600)]);
+/// assert_eq!(merge_range(&[(100, 200), (200, 300)]), vec![(100, 300)]);
+/// assert_eq!(merge_range(&[(100, 200), (201, 300)]), vec![(100, 200), (201, 300)]);
+/// assert_eq!(merge_range(&[]), vec![]);
+/// ```
+pub fn merge_range(vec: &[(i64, i64)]) -> Vec<(i64, i64)> {
+    let mut merged = vec![];
+    if vec.is_empty() {
+        return merged;
+    }
+    
+    let mut start = vec[0].0;
+    let mut end = vec[0].1;
+    
+    for &(s, e) in vec.iter().skip(1) {
+        if s <= end {
+            end = end.max(e);
+        } else {
+            merged.push((start, end));
+            start = s;
+            end = e;
+        }
+    }
+    
+    merged.push((start, end));
+    
+    merged
+}
+/// Reference blocks `[start, end)` of an alignment starting at `offset`, merged where they touch.
+///
+/// Only a skip of at least one base (`N` with a length above 0) separates two blocks. Consecutive
+/// `M`, `=`, `X` and `D` operations form one block, and an operation of length 0, such as the
+/// `0N` some aligners emit, moves nothing and is ignored:
+///
+/// ```
+/// use locreadion::cigar_parser;
+/// assert_eq!(cigar_parser("10M10M", 0), Ok(vec![(0, 20)]));
+/// assert_eq!(cigar_parser("10M0N10M", 0), Ok(vec![(0, 20)]));
+/// assert_eq!(cigar_parser("10M0M10M", 0), Ok(vec![(0, 20)]));
+/// assert_eq!(cigar_parser("10M5D10M", 0), Ok(vec![(0, 25)]));
+/// assert_eq!(cigar_parser("10M5N10M", 0), Ok(vec![(0, 10), (15, 25)]));
+/// ```
+///
+/// Clips and insertions consume no reference, so they neither move a block nor split it,
+/// and an `N` splits the blocks around it whatever insertions sit next to it:
+///
+/// ```
+/// use locreadion::cigar_parser;
+/// assert_eq!(cigar_parser("10S50M2I30M5S", 100), Ok(vec![(100, 180)]));
+/// assert_eq!(cigar_parser("20M3I20M", 0), Ok(vec![(0, 40)]));
+/// assert_eq!(cigar_parser("5H3S20M4H", 0), Ok(vec![(0, 20)]));
+/// assert_eq!(cigar_parser("10M2I5N10M", 0), Ok(vec![(0, 10), (15, 25)]));
+/// assert_eq!(cigar_parser("10M5N2I10M1I5N5M", 0), Ok(vec![(0, 10), (15, 25), (30, 35)]));
+/// ```
+///
+/// Malformed CIGARs are an error, never a panic:
+///
+/// ```
+/// use locreadion::cigar_parser;
+/// assert_eq!(cigar_parser("20M100N30M", 300), Ok(vec![(300, 320), (420, 450)]));
+/// // minimap2 writes matches and mismatches as `=` and `X`, padding consumes nothing
+/// assert_eq!(cigar_parser("50=2X48=", 300), cigar_parser("100M", 300));
+/// assert_eq!(cigar_parser("50M3P50M", 300), cigar_parser("100M", 300));
+/// assert_eq!(cigar_parser("", 300), Ok(vec![]));
+/// assert!(cigar_parser("*", 300).is_err());
+/// assert!(cigar_parser("M10", 300).is_err());
+/// assert!(cigar_parser("10M5", 300).is_err());
+/// assert!(cigar_parser("1é0M", 300).is_err());
+/// ```
+pub fn cigar_parser(cigar: &str, offset: i64) -> Result<Vec<(i64, i64)>, String> {
+    let blocks: Vec<(i64, i64)> = cigar_operations(cigar, offset)?.iter().map(|&(start, end, _)| (start, end)).collect();
+    Ok(merge_range(&blocks))
+}
+
+// the reference interval of every M, =, X and D operation, unmerged, with the read position
+// of its first base, `None` for deletions; the checks of cigar_parser are all made here
+fn cigar_operations(cigar: &str, offset: i64) -> Result<Vec<(i64, i64, Option<i64>)>, String> {
+    if cigar == "*" {
+        return Err(format!("unavailable CIGAR at {}", offset));
+    }
+    if offset < 0 {
+        return Err(format!("negative alignment start {} of CIGAR {}", offset, cigar));
+    }
+    // long-read CIGARs hold thousands of operations, size the buffer once
+    let mut operations = Vec::with_capacity(cigar.bytes().filter(|b| b.is_ascii_alphabetic()).count());
+    let mut start: i64 = 0;
+    let mut end: i64 = 0;
+    let mut query: i64 = 0;
+    // one pass over the bytes, the operation length is accumulated digit by digit; real
+    // coordinates are far below i64 max, only a corrupt record can overflow and is reported
+    let mut num: Option<i64> = None;
+    for op in cigar.bytes() {
+        if op.is_ascii_digit() {
+            num = num.unwrap_or(0).checked_mul(10).and_then(|n| n.checked_add((op - b'0') as i64));
+            if num.is_none() {
+                return Err(format!("invalid operation length in CIGAR {} at {}", cigar, offset));
+            }
+            continue;
+        }
+        let len = num.take()
+            .ok_or_else(|| format!("invalid operation length in CIGAR {} at {}", cigar, offset))?;
+        // an empty operation would otherwise add a block of length 0, which is rejected below
+        if len == 0 && b"MIDNSHP=X".contains(&op) {
+            continue;
+        }
+        let advance = || end.checked_add(len)
+            .filter(|end| end.checked_add(offset).is_some())
+            .ok_or_else(|| format!("CIGAR {} at {} runs past the largest coordinate", cigar, offset));
+        // the read is never longer than the reference, so this only fails on a corrupt record too
+        let advance_query = || query.checked_add(len)
+            .ok_or_else(|| format!("CIGAR {} at {} runs past the longest read", cigar, offset));
+        match op {
+            // reference-consuming, aligned bases
+            b'M' | b'=' | b'X' => {
+                end = advance()?;
+                operations.push((start + offset, end + offset, Some(query)));
+                start = end;
+                query = advance_query()?;
+            }
+            // deleted bases cover the reference but have no read position
+            b'D' => {
+                end = advance()?;
+                operations.push((start + offset, end + offset, None));
+                start = end;
+            }
+            // skipped reference, e.g. an intron
+            b'N' => {
+                end = advance()?;
+                start = end;
+            }
+            // query-only, the reference position does not move
+            b'I' | b'S' => query = advance_query()?,
+            // hard clips and padding are not in the read either
+            b'H' | b'P' => {}
+            _ => return Err(format!("unknown operation {:?} in CIGAR {} at {}", op as char, cigar, offset)),
+        }
+    }
+    if num.is_some() {
+        return Err(format!("missing operation at the end of CIGAR {} at {}", cigar, offset));
+    }
+    let blocks: Vec<(i64, i64)> = operations.iter().map(|&(start, end, _)| (start, end)).collect();
+    check_blocks(&blocks, cigar, offset)?;
+    Ok(operations)
+}
+
+/// Checks that the blocks parsed from `cigar` at `offset` have a positive length and never
+/// step backwards, which [`merge_range`] and the coverage functions rely on; the error names
+/// the CIGAR and its alignment start.
+///
+/// [`cigar_parser`] runs it on every CIGAR before merging. Operation lengths are never
+/// negative there, so only a bug in the parser can fail it, and it fails loudly instead of
+/// giving silently wrong coverage:
+///
+/// ```
+/// use locreadion::check_blocks;
+/// assert_eq!(check_blocks(&[(100, 120), (120, 150), (250, 300)], "20M30M100N50M", 100), Ok(()));
+/// assert_eq!(check_blocks(&[], "", 100), Ok(()));
+/// assert_eq!(check_blocks(&[(100, 120), (150, 140)], "20M30N10M", 100),
+///     Err("non-positive block 150-140 from CIGAR 20M30N10M at 100".to_string()));
+/// assert_eq!(check_blocks(&[(100, 120), (110, 140)], "20M30N10M", 100),
+///     Err("non-monotonic blocks (100, 120) and (110, 140) from CIGAR 20M30N10M at 100".to_string()));
+/// ```
+pub fn check_blocks(blocks: &[(i64, i64)], cigar: &str, offset: i64) -> Result<(), String> {
+    if let Some(&(s, e)) = blocks.iter().find(|&&(s, e)| e <= s) {
+        return Err(format!("non-positive block {}-{} from CIGAR {} at {}", s, e, cigar, offset));
+    }
+    if let Some(w) = blocks.windows(2).find(|w| w[1].0 < w[0].1) {
+        return Err(format!("non-monotonic blocks {:?} and {:?} from CIGAR {} at {}", w[0], w[1], cigar, offset));
+    }
+    Ok(())
+}
+
+// negative or empty intervals only come from corrupt input, and would give garbage coverage
+fn check_interval(what: &str, start: i64, end: i64) -> Result<(), String> {
+    if start < 0 || end <= start {
+        return Err(format!("invalid {} interval {}-{}", what, start, end));
+    }
+    Ok(())
+}
+
+/// Coverage of region `[c, d)` by the read aligned at `[a, b)`, an error when either interval
+/// is negative or empty, or when the CIGAR does not parse or does not end at `b`.
+///
+/// ```
+/// use locreadion::{CoverageMetric, calc_coverage};
+/// assert_eq!(calc_coverage(100, 150, 120, 200, "50M", CoverageMetric::Total), Ok(30));
+/// // soft clipping does not move the reference end, so the end reported for this read is off
+/// assert!(calc_coverage(100, 155, 120, 200, "5S50M", CoverageMetric::Total).is_err());
+/// assert!(calc_coverage(100, 150, 200, 120, "50M", CoverageMetric::Total).is_err());
+/// assert!(calc_coverage(-50, 0, 0, 200, "50M", CoverageMetric::Total).is_err());
+/// assert!(calc_coverage(100, 150, -20, 200, "50M", CoverageMetric::Total).is_err());
+/// assert!(calc_coverage(100, 150, 120, 200, "9223372036854775807M", CoverageMetric::Total).is_err());
+/// ```
+pub fn calc_coverage(a: i64, b: i64, c: i64, d: i64, cigar: &str, metric: CoverageMetric) -> Result<i64, String> {
+    let blocks: Vec<(i64, i64)> = alignment_operations(a, b, c, d, cigar)?.iter().map(|&(start, end, _)| (start, end)).collect();
+    Ok(block_coverage(&merge_range(&blocks), c, d, metric))
+}
+
+// the checks of calc_coverage, shared by the QUAL-weighted coverage
+fn alignment_operations(a: i64, b: i64, c: i64, d: i64, cigar: &str) -> Result<Vec<(i64, i64, Option<i64>)>, String> {
+    check_interval("alignment", a, b)?;
+    check_interval("region", c, d)?;
+    let operations = cigar_operations(cigar, a)?;
+    match operations.last() {
+        Some(&(_, end, _)) if end == b => Ok(operations),
+        Some(&(_, end, _)) => Err(format!("alignment end {} does not match the end {} of CIGAR {} at {}", b, end, cigar, a)),
+        None => Err(format!("no aligned bases in CIGAR {} at {}", cigar, a)),
+    }
+}
+
+/// [`calc_coverage`] with every covered base counted with its Phred score from `qual`, the
+/// SAM QUAL field, instead of 1, for `--qual-weight`. Deleted bases take the mean quality of
+/// the read and a missing QUAL (`*`) gives the plain coverage. All candidates of a read share
+/// its QUAL, so the scale does not matter for the arg_max.
+///
+/// The CIGAR and both intervals are checked as in [`calc_coverage`], and a QUAL shorter than
+/// the read the CIGAR describes is an error too:
+///
+/// ```
+/// use locreadion::{CoverageMetric, qual_coverage};
+/// // Phred 40 (`I`) on the first 10 bases and 10 (`+`) on the other 10
+/// let qual = "IIIIIIIIII++++++++++";
+/// assert_eq!(qual_coverage(100, 120, 100, 200, "20M", qual, CoverageMetric::Total), Ok(500.0));
+/// assert_eq!(qual_coverage(100, 120, 110, 200, "20M", qual, CoverageMetric::Total), Ok(100.0));
+/// // the deleted bases count with the mean of 25
+/// assert_eq!(qual_coverage(100, 122, 100, 200, "10M2D10M", qual, CoverageMetric::Total), Ok(550.0));
+/// assert_eq!(qual_coverage(100, 130, 100, 200, "10M10N10M", qual, CoverageMetric::MaxBlock), Ok(400.0));
+/// assert_eq!(qual_coverage(100, 120, 110, 200, "20M", "*", CoverageMetric::Total), Ok(10.0));
+/// assert!(qual_coverage(100, 120, 100, 200, "5S20M", qual, CoverageMetric::Total).is_err());
+/// assert!(qual_coverage(100, 120, 100, 200, "20Q", qual, CoverageMetric::Total).is_err());
+/// assert!(qual_coverage(100, 125, 100, 200, "25M", qual, CoverageMetric::Total).is_err());
+/// ```
+pub fn qual_coverage(a: i64, b: i64, c: i64, d: i64, cigar: &str, qual: &str, metric: CoverageMetric) -> Result<f64, String> {
+    if qual == "*" {
+        return calc_coverage(a, b, c, d, cigar, metric).map(|cov| cov as f64);
+    }
+    let operations = alignment_operations(a, b, c, d, cigar)?;
+    let phred: Vec<f64> = qual.bytes().map(|q| q.saturating_sub(33) as f64).collect();
+    let mean_phred = phred.iter().sum::<f64>() / phred.len().max(1) as f64;
+    let short = || format!("QUAL shorter than CIGAR {} at {}", cigar, a);
+    // operations that touch form one block like in cigar_parser, a skip starts the next
+    let mut blocks: Vec<f64> = vec![];
+    let mut block_end = None;
+    for (start, end, query) in operations {
+        if block_end != Some(start) {
+            blocks.push(0.0);
+        }
+        block_end = Some(end);
+        let btm = start.max(c);
+        let top = end.min(d);
+        if top <= btm {
+            continue;
+        }
+        let bases = match query {
+            Some(query) => {
+                let q0 = usize::try_from(query + btm - start).map_err(|_| short())?;
+                let q1 = usize::try_from(query + top - start).map_err(|_| short())?;
+                phred.get(q0..q1).ok_or_else(short)?.iter().sum::<f64>()
+            }
+            None => (top - btm) as f64 * mean_phred,
+        };
+        *blocks.last_mut().unwrap() += bases;
+    }
+    Ok(match metric {
+        CoverageMetric::Total => blocks.iter().sum(),
+        CoverageMetric::MaxBlock => blocks.into_iter().fold(0.0, f64::max),
+    })
+}
+
+/// `cigar` with its skipped reference (`N`) written as deletions (`D`), so [`cigar_parser`]
+/// and the coverage functions take the whole span of a spliced alignment as covered, as
+/// `bedtools intersect` does without `-split`:
+///
+/// ```
+/// use locreadion::{CoverageMetric, calc_coverage, unsplit_cigar};
+/// assert_eq!(unsplit_cigar("20M100N30M"), "20M100D30M");
+/// // the region [310, 440) misses most of both blocks but lies within the span
+/// assert_eq!(calc_coverage(300, 450, 310, 440, "20M100N30M", CoverageMetric::Total), Ok(30));
+/// assert_eq!(calc_coverage(300, 450, 310, 440, &unsplit_cigar("20M100N30M"), CoverageMetric::Total), Ok(130));
+/// ```
+pub fn unsplit_cigar(cigar: &str) -> String {
+    cigar.replace('N', "D")
+}
+
+/// Exonic span of `cigar`, the reference bases of its M and D blocks; skips (`N`) are not counted:
+///
+/// ```
+/// use locreadion::aligned_span;
+/// assert_eq!(aligned_span("5S20M100N28M2D2M"), Ok(52));
+/// ```
+pub fn aligned_span(cigar: &str) -> Result<i64, String> {
+    Ok(cigar_parser(cigar, 0)?.iter().map(|(s, e)| e - s).sum())
+}
+
+/// Coverage of region `[c, d)` by the `window` reference bases at the read's 3' end only,
+/// the start of the alignment for reverse strand reads.
+pub fn three_prime_coverage(a: i64, c: i64, d: i64, cigar: &str, reverse: bool, window: i64, metric: CoverageMetric) -> Result<i64, String> {
+    check_interval("region", c, d)?;
+    let ranges: Vec<(i64, i64)> = cigar_parser(cigar, a)?;
+    let mut remaining = window;
+    let mut windowed: Vec<(i64, i64)> = vec![];
+    let blocks: Box<dyn Iterator<Item = &(i64, i64)>> = if reverse {
+        Box::new(ranges.iter())
+    } else {
+        Box::new(ranges.iter().rev())
+    };
+    for &(s, e) in blocks {
+        if remaining <= 0 {
+            break;
+        }
+        let len = (e - s).min(remaining);
+        windowed.push(if reverse { (s, s + len) } else { (e - len, e) });
+        remaining -= len;
+    }
+    Ok(block_coverage(&windowed, c, d, metric))
+}
+
+/// Sorted, non-overlapping bedGraph intervals of a weight per base, by chromosome.
+#[derive(Clone)]
+pub struct WeightTrack {
+    intervals: HashMap<String, Vec<(i64, i64, f64)>>,
+}
+
+impl WeightTrack {
+    /// Parses a bedGraph, `track` and `#` lines are skipped; the error names the offending
+    /// line, or the overlapping intervals:
+    ///
+    /// ```
+    /// use locreadion::WeightTrack;
+    /// let track = WeightTrack::parse("track type=bedGraph\nchr1\t100\t110\t0.5\n").unwrap();
+    /// assert_eq!(track.integrate("chr1", 90, 110), 15.0);
+    /// assert!(WeightTrack::parse("chr1\t100\t110\t-1\n").is_err());
+    /// assert!(WeightTrack::parse("chr1\t100\t110\t1\nchr1\t105\t120\t1\n").is_err());
+    /// ```
+    pub fn parse(content: &str) -> Result<WeightTrack, String> {
+        let mut intervals: HashMap<String, Vec<(i64, i64, f64)>> = HashMap::new();
+        for line in content.lines().filter(|l| !l.trim().is_empty() && !l.starts_with("track") && !l.starts_with('#')) {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let parsed = match fields.as_slice() {
+                [chr, start, end, value, ..] => match (start.parse::<i64>(), end.parse::<i64>(), value.trim().parse::<f64>()) {
+                    (Ok(start), Ok(end), Ok(value)) if end > start && value >= 0.0 => Some((chr.to_string(), start, end, value)),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match parsed {
+                Some((chr, start, end, value)) => intervals.entry(chr).or_default().push((start, end, value)),
+                None => return Err(format!("line {:?}", line)),
+            }
+        }
+        for (chr, ranges) in intervals.iter_mut() {
+            ranges.sort_by_key(|&(start, _, _)| start);
+            if let Some(w) = ranges.windows(2).find(|w| w[1].0 < w[0].1) {
+                return Err(format!("overlapping intervals on {}: {}-{} and {}-{}", chr, w[0].0, w[0].1, w[1].0, w[1].1));
+            }
+        }
+        Ok(WeightTrack { intervals })
+    }
+
+    /// Sum of the weights over `[start, end)` of `chr`, bases outside the track weigh 1.
+    pub fn integrate(&self, chr: &str, start: i64, end: i64) -> f64 {
+        let ranges = match self.intervals.get(chr) {
+            Some(ranges) => ranges,
+            None => return (end - start) as f64,
+        };
+        let mut total = 0.0;
+        let mut covered = 0;
+        let first = ranges.partition_point(|&(_, e, _)| e <= start);
+        for &(s, e, value) in ranges[first..].iter().take_while(|&&(s, _, _)| s < end) {
+            let overlap = e.min(end) - s.max(start);
+            total += overlap as f64 * value;
+            covered += overlap;
+        }
+        total + (end - start - covered) as f64
+    }
+}
+
+/// Coverage of region `[c, d)` on `chr` with every covered base counted with its value in
+/// `track` instead of 1, for `--weight-track`. Each aligned block costs a binary search plus
+/// one step per track interval it spans, so a fine-grained (per-base) track is noticeably
+/// slower than the plain coverage.
+pub fn track_coverage(a: i64, c: i64, d: i64, cigar: &str, chr: &str, track: &WeightTrack, metric: CoverageMetric) -> Result<f64, String> {
+    let ranges: Vec<(i64, i64)> = cigar_parser(cigar, a)?;
+    let blocks: Vec<f64> = ranges.iter().map(|range| {
+        let btm = range.0.max(c);
+        let top = range.1.min(d);
+        if top > btm {
+            track.integrate(chr, btm, top)
+        } else {
+            0.0
+        }
+    }).collect();
+    Ok(match metric {
+        CoverageMetric::Total => blocks.iter().sum(),
+        CoverageMetric::MaxBlock => blocks.into_iter().fold(0.0, f64::max),
+    })
+}
+
+fn block_coverage(ranges: &[(i64, i64)], c: i64, d: i64, metric: CoverageMetric) -> i64 {
+    let mapped_vec: Vec<i64> = ranges.iter().map(|range| {
+        let btm = range.0.max(c);
+        let top = range.1.min(d);
+        if top > btm {
+            top - btm
+        } else {
+            0
+        }
+    }).collect();
+    match metric {
+        CoverageMetric::Total => mapped_vec.iter().sum(),
+        CoverageMetric::MaxBlock => mapped_vec.into_iter().max().unwrap_or(0),
+    }
+}
+
+/// One output record of the refinement: a read and the region it was assigned to.
+///
+/// The region fields are `None` for the unassigned reads of `--include-unassigned`,
+/// `coverage` is the plain overlap under the given metric, without QUAL, track or prior weights.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assignment {
+    pub chrom: String,
+    pub start: i64,
+    pub end: i64,
+    pub read: String,
+    pub region_start: Option<i64>,
+    pub region_end: Option<i64>,
+    pub region: Option<String>,
+    pub cigar: String,
+    pub coverage: i64,
+    /// `assigned`, `filtered` or `no_overlap` when the result carries a `reason` column
+    pub reason: Option<String>,
+}
+
+/// Iterator over the rows of a collected result dataframe, in output order.
+///
+/// ```no_run
+/// # fn run(result: &polars::prelude::DataFrame) -> polars::prelude::PolarsResult<()> {
+/// use locreadion::{Assignments, CoverageMetric};
+/// for assignment in Assignments::new(result, CoverageMetric::Total)? {
+///     let assignment = assignment?;
+///     println!("{}\t{:?}\t{}", assignment.read, assignment.region, assignment.coverage);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Assignments {
+    chrom: Utf8Chunked,
+    start: Int64Chunked,
+    end: Int64Chunked,
+    read: Utf8Chunked,
+    region_start: Int64Chunked,
+    region_end: Int64Chunked,
+    region: Utf8Chunked,
+    cigar: Utf8Chunked,
+    reason: Option<Utf8Chunked>,
+    metric: CoverageMetric,
+    idx: usize,
+}
+
+impl Assignments {
+    /// Fails if one of the output columns is missing or has the wrong type.
+    pub fn new(result: &DataFrame, metric: CoverageMetric) -> PolarsResult<Assignments> {
+        // single chunks keep the per-row lookups constant time
+        let utf8 = |name: &str| -> PolarsResult<Utf8Chunked> { Ok(result.column(name)?.utf8()?.rechunk()) };
+        let int64 = |name: &str| -> PolarsResult<Int64Chunked> { Ok(result.column(name)?.i64()?.rechunk()) };
+        Ok(Assignments {
+            chrom: utf8("chr")?,
+            start: int64("align_0")?,
+            end: int64("align_1")?,
+            read: utf8("read")?,
+            region_start: int64("region_0")?,
+            region_end: int64("region_1")?,
+            region: utf8("region")?,
+            cigar: utf8("cigar")?,
+            reason: result.column("reason").ok().map(|s| s.utf8().map(|ca| ca.rechunk())).transpose()?,
+            metric,
+            idx: 0,
+        })
+    }
+
+    fn row(&self, idx: usize) -> PolarsResult<Assignment> {
+        let missing = |name: &str| PolarsError::ComputeError(format!("null {} in result row {}", name, idx).into());
+        let chrom = self.chrom.get(idx).ok_or_else(|| missing("chr"))?;
+        let start = self.start.get(idx).ok_or_else(|| missing("align_0"))?;
+        let end = self.end.get(idx).ok_or_else(|| missing("align_1"))?;
+        let read = self.read.get(idx).ok_or_else(|| missing("read"))?;
+        let cigar = self.cigar.get(idx).ok_or_else(|| missing("cigar"))?;
+        let region_start = self.region_start.get(idx);
+        let region_end = self.region_end.get(idx);
+        let coverage = match (region_start, region_end) {
+            (Some(c), Some(d)) => calc_coverage(start, end, c, d, cigar, self.metric)
+                .map_err(|e| PolarsError::ComputeError(e.into()))?,
+            _ => 0,
+        };
+        Ok(Assignment {
+            chrom: chrom.to_string(),
+            start,
+            end,
+            read: read.to_string(),
+            region_start,
+            region_end,
+            region: self.region.get(idx).map(str::to_string),
+            cigar: cigar.to_string(),
+            coverage,
+            reason: self.reason.as_ref().and_then(|r| r.get(idx)).map(str::to_string),
+        })
+    }
+}
+
+impl Iterator for Assignments {
+    type Item = PolarsResult<Assignment>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx >= self.read.len() {
+            return None;
+        }
+        let row = self.row(self.idx);
+        self.idx += 1;
+        Some(row)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.read.len() - self.idx;
+        (remaining, Some(remaining))
+    }
+}
+
+/// Index of the winning candidate among those with the best score, `None` when nothing
+/// could be scored or the tie is dropped.
+///
+/// ```
+/// use locreadion::{TieBreak, break_tie};
+/// use polars::prelude::*;
+/// let scores = Float64Chunked::from_slice("scores", &[30.0, 50.0, 50.0, 50.0]);
+/// let regions = Utf8Chunked::from_slice("regions", &["A", "C", "B", "D"]);
+/// let starts = Int64Chunked::from_slice("starts", &[0, 0, 0, 0]);
+/// let ends = Int64Chunked::from_slice("ends", &[100, 300, 200, 150]);
+/// let pick = |tie_break| break_tie(&scores, &regions, &starts, &ends, tie_break);
+/// assert_eq!(pick(TieBreak::First), Some(1));
+/// assert_eq!(pick(TieBreak::RegionName), Some(2));
+/// assert_eq!(pick(TieBreak::ShortestRegion), Some(3));
+/// assert_eq!(pick(TieBreak::Drop), None);
+/// ```
+///
+/// Candidates without a score never win, and a read none of whose candidates could be
+/// scored gets `None`:
+///
+/// ```
+/// use locreadion::{TieBreak, break_tie};
+/// use polars::prelude::*;
+/// let regions = Utf8Chunked::from_slice("regions", &["A", "B", "C"]);
+/// let starts = Int64Chunked::from_slice("starts", &[0, 0, 0]);
+/// let ends = Int64Chunked::from_slice("ends", &[100, 100, 100]);
+/// let mixed: Float64Chunked = [None, Some(20.0), Some(10.0)].into_iter().collect();
+/// assert_eq!(break_tie(&mixed, &regions, &starts, &ends, TieBreak::First), Some(1));
+/// assert_eq!(break_tie(&mixed, &regions, &starts, &ends, TieBreak::Drop), Some(1));
+/// let unscored: Float64Chunked = [None, None, None].into_iter().collect();
+/// assert_eq!(break_tie(&unscored, &regions, &starts, &ends, TieBreak::First), None);
+/// assert_eq!(break_tie(&unscored, &regions, &starts, &ends, TieBreak::RegionName), None);
+/// ```
+pub fn break_tie(scores: &Float64Chunked, regions: &Utf8Chunked, starts: &Int64Chunked, ends: &Int64Chunked, tie_break: TieBreak) -> Option<IdxSize> {
+    let best = scores.into_iter().flatten().fold(f64::NEG_INFINITY, f64::max);
+    let tied: Vec<usize> = scores.into_iter()
+        .enumerate()
+        .filter(|(_, score)| *score == Some(best))
+        .map(|(i, _)| i)
+        .collect();
+    let winner = match tie_break {
+        TieBreak::First => tied.first().copied(),
+        TieBreak::RegionName => tied.iter().copied().min_by_key(|&i| regions.get(i)),
+        TieBreak::ShortestRegion => tied.iter().copied()
+            .min_by_key(|&i| ends.get(i).zip(starts.get(i)).map(|(end, start)| end - start)),
+        TieBreak::Drop => if tied.len() == 1 { tied.first().copied() } else { None },
+    };
+    winner.map(|i| i as IdxSize)
+}
+
+/// `scores` with the best-scoring candidates from a lower priority source taken out, so
+/// [`break_tie`] only sees the tied candidates of the highest priority source among them.
+///
+/// Sources are BED file names, matched to `priority` without their `.bed` or `.bed.gz`
+/// extension; sources missing from `priority` come after all listed ones.
+///
+/// ```
+/// use locreadion::{TieBreak, break_tie, prefer_sources};
+/// use polars::prelude::*;
+/// let scores = Float64Chunked::from_slice("scores", &[30.0, 30.0, 10.0]);
+/// let sources = Utf8Chunked::from_slice("sources", &["gene.bed", "exon.bed", "exon.bed"]);
+/// let regions = Utf8Chunked::from_slice("regions", &["G1", "E1", "E2"]);
+/// let starts = Int64Chunked::from_slice("starts", &[0, 0, 0]);
+/// let ends = Int64Chunked::from_slice("ends", &[100, 100, 100]);
+/// let preferred = prefer_sources(&scores, &sources, &["exon".to_string(), "gene".to_string()]);
+/// assert_eq!(break_tie(&preferred, &regions, &starts, &ends, TieBreak::First), Some(1));
+/// ```
+pub fn prefer_sources(scores: &Float64Chunked, sources: &Utf8Chunked, priority: &[String]) -> Float64Chunked {
+    let rank = |source: Option<&str>| {
+        let source = source.unwrap_or_default();
+        let stem = source.strip_suffix(".gz").unwrap_or(source);
+        let stem = stem.strip_suffix(".bed").unwrap_or(stem);
+        priority.iter().position(|p| p == stem).unwrap_or(priority.len())
+    };
+    let best = scores.into_iter().flatten().fold(f64::NEG_INFINITY, f64::max);
+    let best_rank = scores.into_iter()
+        .zip(sources)
+        .filter(|(score, _)| *score == Some(best))
+        .map(|(_, source)| rank(source))
+        .min();
+    scores.into_iter()
+        .zip(sources)
+        .map(|(score, source)| match score {
+            Some(score) if score == best && Some(rank(source)) != best_rank => None,
+            score => score,
+        })
+        .collect()
+}
+
+/// [`calc_coverage`] of every row of a joined frame, null where a field is missing.
+pub fn coverage_column(metric: CoverageMetric) -> Expr {
+    as_struct(&[col("align_0"), col("align_1"), col("region_0"), col("region_1"), col("cigar")])
+        .map(move |s| {
+            let ca = s.struct_()?;
+            let fields = ca.fields();
+            let cov: Vec<Option<i64>> = fields[0].i64()?.into_iter()
+                .zip(fields[1].i64()?)
+                .zip(fields[2].i64()?)
+                .zip(fields[3].i64()?)
+                .zip(fields[4].utf8()?)
+                .map(|((((a, b), c), d), cigar)| match (a, b, c, d, cigar) {
+                    (Some(a), Some(b), Some(c), Some(d), Some(cigar)) => calc_coverage(a, b, c, d, cigar, metric)
+                        .map(Some)
+                        .map_err(|e| PolarsError::ComputeError(e.into())),
+                    _ => Ok(None),
+                })
+                .collect::<PolarsResult<_>>()?;
+            Ok(Some(Int64Chunked::from_iter(cov).into_series()))
+        }, GetOutput::from_type(DataType::Int64))
+}
+
+/// `result` with the alignment and region starts, `align_0` and `region_0`, shifted from
+/// the 0-based coordinates the refinement works in to `base`:
+///
+/// ```
+/// use locreadion::{CoordBase, shift_starts};
+/// use polars::prelude::*;
+/// let result = df!("align_0" => [99i64], "align_1" => [150i64], "region_0" => [119i64], "region_1" => [200i64]).unwrap();
+/// let zero = shift_starts(&result, CoordBase::Zero).unwrap();
+/// assert_eq!(zero["align_0"].i64().unwrap().get(0), Some(99));
+/// assert_eq!(zero["region_0"].i64().unwrap().get(0), Some(119));
+/// let one = shift_starts(&result, CoordBase::One).unwrap();
+/// assert_eq!(one["align_0"].i64().unwrap().get(0), Some(100));
+/// assert_eq!(one["region_0"].i64().unwrap().get(0), Some(120));
+/// assert_eq!(one["align_1"].i64().unwrap().get(0), Some(150));
+/// ```
+pub fn shift_starts(result: &DataFrame, base: CoordBase) -> PolarsResult<DataFrame> {
+    if base == CoordBase::Zero {
+        return Ok(result.clone());
+    }
+    let schema = result.schema();
+    let starts: Vec<Expr> = ["align_0", "region_0"].into_iter()
+        .filter(|name| schema.contains(name))
+        .map(|name| (col(name) + lit(1)).alias(name))
+        .collect();
+    result.clone().lazy().with_columns(starts).collect()
+}
+
+/// `lf` without the rows of the reads named in `excluded`, e.g. spike-in controls or known
+/// artifacts, matched on the `read` column:
+///
+/// ```
+/// use locreadion::exclude_reads;
+/// use polars::prelude::*;
+/// let lf = df!("read" => ["r1", "spike", "r2"], "region" => ["A1", "A1", "B2"]).unwrap().lazy();
+/// let kept = exclude_reads(lf, &["spike".to_string()]).collect().unwrap();
+/// let reads: Vec<_> = kept["read"].utf8().unwrap().into_iter().flatten().collect();
+/// assert_eq!(reads, ["r1", "r2"]);
+/// ```
+pub fn exclude_reads(lf: LazyFrame, excluded: &[String]) -> LazyFrame {
+    if excluded.is_empty() {
+        return lf;
+    }
+    lf.filter(col("read").is_in(lit(Series::new("excluded", excluded))).not())
+}
+
+/// `lf` restricted to the rows of the regions named in `kept`, e.g. a small gene panel, all of
+/// them when `kept` is empty:
+///
+/// ```
+/// use locreadion::keep_regions;
+/// use polars::prelude::*;
+/// let lf = df!("read" => ["r1", "r2", "r2"], "region" => ["A1", "A2", "B2"]).unwrap().lazy();
+/// let kept = keep_regions(lf, &["A2".to_string()]).collect().unwrap();
+/// let regions: Vec<_> = kept["region"].utf8().unwrap().into_iter().flatten().collect();
+/// assert_eq!(regions, ["A2"]);
+/// ```
+pub fn keep_regions(lf: LazyFrame, kept: &[String]) -> LazyFrame {
+    if kept.is_empty() {
+        return lf;
+    }
+    lf.filter(col("region").is_in(lit(Series::new("kept", kept))))
+}
+
+/// The wall-clock time spent on each BED file, one row per file as `source`, `status`, how
+/// the file ended such as `screened` or `skipped`, and `seconds`, slowest first:
+///
+/// ```
+/// use std::time::Duration;
+/// use locreadion::timing_table;
+/// use polars::prelude::*;
+/// let timings = vec![
+///     ("a.bed".to_string(), "screened", Duration::from_millis(250)),
+///     ("b.bed".to_string(), "skipped", Duration::from_secs(3)),
+/// ];
+/// let table = timing_table(&timings).unwrap();
+/// assert_eq!(table.height(), timings.len());
+/// assert_eq!(table["source"].utf8().unwrap().get(0), Some("b.bed"));
+/// assert_eq!(table["status"].utf8().unwrap().get(0), Some("skipped"));
+/// assert_eq!(table["seconds"].f64().unwrap().get(1), Some(0.25));
+/// ```
+pub fn timing_table(timings: &[(String, &str, Duration)]) -> PolarsResult<DataFrame> {
+    let sources: Vec<&str> = timings.iter().map(|(source, _, _)| source.as_str()).collect();
+    let statuses: Vec<&str> = timings.iter().map(|&(_, status, _)| status).collect();
+    let seconds: Vec<f64> = timings.iter().map(|(_, _, elapsed)| elapsed.as_secs_f64()).collect();
+    df!("source" => sources, "status" => statuses, "seconds" => seconds)?
+        .sort(["seconds"], true)
+}
+
+/// What the SAM POS of an alignment is above its BED start: SAM is 1-based and BED 0-based,
+/// so [`join_overlaps`] matches the records of an alignment where `POS = start + 1`.
+pub const SAM_POS_OFFSET: i64 = 1;
+
+/// How many alignments of the BED side [`join_overlaps`] loses, see [`check_join`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JoinCheck {
+    /// Distinct alignments, by read, chromosome and start, of the BED side
+    pub alignments: usize,
+    /// Those without a SAM record at `start + SAM_POS_OFFSET`
+    pub unmatched: usize,
+    /// The most common difference of SAM POS and BED start among the unmatched alignments
+    /// of reads found in the SAM side, `None` when there is none
+    pub offset: Option<i64>,
+}
+
+/// Counts the alignments of `bed_df` that [`join_overlaps`] drops because no SAM record of
+/// the read starts at the matching position, laid out as described there. Both sides come
+/// from the same intersect, so a loss means the coordinates disagree, and `offset` tells
+/// by how much:
+///
+/// ```
+/// use polars::prelude::*;
+/// use locreadion::{JoinCheck, check_join};
+///
+/// # fn main() -> PolarsResult<()> {
+/// let bam_df = df!(
+///     "column_1" => &["r1", "r2"],
+///     "column_3" => &["chr1", "chr1"],
+///     "column_4" => &[101i64, 301],
+/// )?;
+/// let bed_df = |starts: [i64; 2]| df!(
+///     "column_1" => &["chr1", "chr1"],
+///     "column_2" => &starts,
+///     "column_4" => &["r1", "r2"],
+/// );
+/// let expected = check_join(&bed_df([100, 300])?, &bam_df)?;
+/// assert_eq!(expected, JoinCheck { alignments: 2, unmatched: 0, offset: None });
+/// // 1-based starts on the BED side would lose every read
+/// let shifted = check_join(&bed_df([101, 301])?, &bam_df)?;
+/// assert_eq!(shifted, JoinCheck { alignments: 2, unmatched: 2, offset: Some(0) });
+/// # Ok(())
+/// # }
+/// ```
+pub fn check_join(bed_df: &DataFrame, bam_df: &DataFrame) -> PolarsResult<JoinCheck> {
+    // hashed directly, a lazy anti-join over the string keys costs as much as the join itself
+    let mut sam_positions: HashMap<(&str, &str), Vec<i64>> = HashMap::new();
+    let sam_records = bam_df.column("column_1")?.utf8()?.into_iter()
+        .zip(bam_df.column("column_3")?.utf8()?)
+        .zip(bam_df.column("column_4")?.i64()?);
+    for ((read, chr), pos) in sam_records {
+        if let (Some(read), Some(chr), Some(pos)) = (read, chr, pos) {
+            sam_positions.entry((read, chr)).or_default().push(pos);
+        }
+    }
+    let mut seen: HashSet<(&str, &str, i64)> = HashSet::new();
+    let mut unmatched = 0;
+    let mut offsets: HashMap<i64, usize> = HashMap::new();
+    let bed_alignments = bed_df.column("column_4")?.utf8()?.into_iter()
+        .zip(bed_df.column("column_1")?.utf8()?)
+        .zip(bed_df.column("column_2")?.i64()?);
+    for ((read, chr), start) in bed_alignments {
+        let (Some(read), Some(chr), Some(start)) = (read, chr, start) else {
+            continue;
+        };
+        if !seen.insert((read, chr, start)) {
+            continue;
+        }
+        let positions = sam_positions.get(&(read, chr)).map(Vec::as_slice).unwrap_or_default();
+        if !positions.contains(&(start + SAM_POS_OFFSET)) {
+            unmatched += 1;
+            for &pos in positions {
+                *offsets.entry(pos - start).or_default() += 1;
+            }
+        }
+    }
+    let offset = offsets.into_iter()
+        .max_by_key(|&(offset, n)| (n, std::cmp::Reverse(offset)))
+        .map(|(offset, _)| offset);
+    Ok(JoinCheck { alignments: seen.len(), unmatched, offset })
+}
+
+/// Joins the `bedtools intersect -wo -split -bed` output of one BED file with the SAM
+/// records of the same reads, both read without a header so their columns are named
+/// `column_1`, `column_2` and so on.
+///
+/// `bed_df` needs the read's chromosome, start, end and name (`column_1` to `column_4`)
+/// and the region's start, end and name (`column_14` to `column_16`), `bam_df` needs
+/// QNAME, RNAME, POS and CIGAR (`column_1`, `column_3`, `column_4`, `column_6`). QUAL
+/// (`column_11`), FLAG (`column_2`) and the `umi` and `weight` columns are kept as `qual`, `flag`,
+/// `umi` and `weight` when present.
+/// The result has the columns `chr`, `align_0`, `align_1`, `read`, `region_0`,
+/// `region_1`, `region` and `cigar`, one row per read and overlapping region, then the
+/// read's strand from the BED12 strand field (`column_6` of `bed_df`) as `strand` when present:
+///
+/// ```
+/// use polars::prelude::*;
+/// use locreadion::{JoinStrategy, join_overlaps};
+///
+/// # fn main() -> PolarsResult<()> {
+/// let bam_df = df!(
+///     "column_1" => &["r1", "r2"],
+///     "column_3" => &["chr1", "chr1"],
+///     "column_4" => &[101i64, 301],
+///     "column_6" => &["50M", "50M"],
+/// )?;
+/// let bed_df = df!(
+///     "column_1" => &["chr1", "chr1"],
+///     "column_2" => &[100i64, 300],
+///     "column_3" => &[150i64, 350],
+///     "column_4" => &["r1", "r2"],
+///     "column_6" => &["+", "-"],
+///     "column_14" => &[120i64, 280],
+///     "column_15" => &[200i64, 400],
+///     "column_16" => &["A1", "A2"],
+/// )?;
+/// let joined = join_overlaps(bed_df, bam_df, JoinStrategy::Serial).sort("read", Default::default()).collect()?;
+/// assert_eq!(joined["strand"].utf8()?.into_iter().collect::<Vec<_>>(), [Some("+"), Some("-")]);
+/// assert_eq!(joined["cigar"].utf8()?.get(1), Some("50M"));
+/// # Ok(())
+/// # }
+/// ```
+pub fn join_overlaps(bed_df: DataFrame, bam_df: DataFrame, join_strategy: JoinStrategy) -> LazyFrame {
+    let mut columns = vec![
+        col("chr"), col("align_0"), col("align_1"), col("read"), 
+        col("region_0"), col("region_1"), col("region"), col("cigar"), ];
+    // renamed before the join, the CIGAR is `column_6` of the SAM side
+    let has_strand = bed_df.column("column_6").is_ok();
+    let bed_df = if has_strand {
+        bed_df.lazy().rename(["column_6"], ["strand"])
+    } else {
+        bed_df.lazy()
+    };
+    if has_strand {
+        columns.push(col("strand"));
+    }
+    if bam_df.column("column_11").is_ok() {
+        columns.push(col("column_11").alias("qual"));
+    }
+    if bam_df.column("umi").is_ok() {
+        columns.push(col("umi"));
+    }
+    if bam_df.column("weight").is_ok() {
+        columns.push(col("weight"));
+    }
+    if bam_df.column("column_2").is_ok() {
+        // the SAM FLAG clashes with the BED start column and gets the join suffix
+        columns.push(col("column_2_right").alias("flag"));
+    }
+    bed_df
+        .join_builder()
+        .with(bam_df.lazy())
+        .left_on([col("column_4"), col("column_1")])
+        .right_on([col("column_1"), col("column_3")])
+        .how(JoinType::Inner)
+        .allow_parallel(join_strategy != JoinStrategy::Serial)
+        .force_parallel(join_strategy == JoinStrategy::Parallel)
+        .finish()
+        .filter(
+            (col("column_2") + lit(SAM_POS_OFFSET)).eq(col("column_4_right")),
+        )
+        .rename([
+            "column_1", "column_2", 
+            "column_3", "column_4", 
+            "column_14", "column_15", 
+            "column_16", "column_6", ],
+        [
+            "chr", "align_0", "align_1", "read", 
+            "region_0", "region_1", "region", "cigar"
+        ])
+        .select(columns)
+}
+
+
+// the candidate fields every score reads, then the one its weighting needs
+fn score_fields(opts: &Options) -> Vec<Expr> {
+    let mut fields = vec![col("align_0"), col("align_1"), col("region_0"), col("region_1"), col("cigar"), col("region")];
+    if opts.qual_weight {
+        fields.push(col("qual"));
+    } else if opts.track.is_some() {
+        fields.push(col("chr"));
+    } else if opts.three_prime.is_some() {
+        fields.push(col("flag"));
+    }
+    fields
+}
+
+// the score of every row of the struct of score_fields, null where a field is missing
+fn score_udf(opts: &Options) -> impl Fn(Series) -> PolarsResult<Option<Series>> + Clone + Send + Sync + 'static {
+    let metric = opts.metric;
+    let fraction = opts.fraction;
+    let qual_weight = opts.qual_weight;
+    let three_prime = opts.three_prime;
+    let track = opts.track.clone().map(Arc::new);
+    let prior = Arc::new(opts.prior.clone());
+    move |s: Series| {
+        let ca = s.struct_()?;
+        let fields = ca.fields();
+        // QUAL, the track and the 3' window are exclusive, so the chromosome and FLAG take the same slot
+        let ca_qual: Box<dyn Iterator<Item = Option<&str>>> = if qual_weight {
+            Box::new(fields[6].utf8()?.into_iter())
+        } else {
+            Box::new(std::iter::repeat(Some("*")))
+        };
+        let ca_chr: Box<dyn Iterator<Item = Option<&str>>> = if !qual_weight && track.is_some() {
+            Box::new(fields[6].utf8()?.into_iter())
+        } else {
+            Box::new(std::iter::repeat(Some("")))
+        };
+        let ca_flag: Box<dyn Iterator<Item = Option<i64>>> = if !qual_weight && track.is_none() && three_prime.is_some() {
+            Box::new(fields[6].i64()?.into_iter())
+        } else {
+            Box::new(std::iter::repeat(Some(0)))
+        };
+        // the rows are independent, they are gathered first and scored over the rayon pool
+        let rows: Vec<_> = fields[0].i64()?.into_iter()
+            .zip(fields[1].i64()?)
+            .zip(fields[2].i64()?)
+            .zip(fields[3].i64()?)
+            .zip(fields[4].utf8()?)
+            .zip(fields[5].utf8()?)
+            .zip(ca_qual)
+            .zip(ca_chr)
+            .zip(ca_flag)
+            .collect();
+        let out: Vec<Option<f64>> = rows
+            .into_par_iter()
+            .map(|((((((((a, b), c), d), cigar), region), qual), chr), flag)| match (a, b, c, d, cigar, region, qual, chr, flag) {
+                (Some(a), Some(b), Some(c), Some(d), Some(cigar), Some(region), Some(qual), Some(chr), Some(flag)) => {
+                    let mut weight = prior.get(region).copied().unwrap_or(1.0);
+                    if fraction {
+                        // the read is the same for every candidate, only the region length tells them apart
+                        weight /= (d - c) as f64;
+                    }
+                    let cov = match (track.as_deref(), three_prime) {
+                        _ if qual_weight => qual_coverage(a, b, c, d, cigar, qual, metric),
+                        (Some(track), _) => track_coverage(a, c, d, cigar, chr, track, metric),
+                        (None, Some(window)) => three_prime_coverage(a, c, d, cigar, flag & 16 != 0, window, metric)
+                            .map(|cov| cov as f64),
+                        (None, None) => calc_coverage(a, b, c, d, cigar, metric).map(|cov| cov as f64),
+                    };
+                    cov
+                        .map(|cov| Some(cov * weight))
+                        .map_err(|e| PolarsError::ComputeError(e.into()))
+                }
+                _ => Ok(None),
+            })
+            .collect::<PolarsResult<_>>()?;
+        Ok(Some(Float64Chunked::from_iter(out).into_series()))
+    }
+}
+
+// the frame with its columns cast back to `schema`, an empty aggregation loses their types
+fn with_schema(lf: LazyFrame, schema: &Schema) -> LazyFrame {
+    lf.select(schema.iter().map(|(name, dtype)| col(name).cast(dtype.clone())).collect::<Vec<_>>())
+}
+
+/// The candidates of a joined frame split into the reads overlapping a single region and those
+/// overlapping several, with the region of each ambiguous read picked by the arg_max of its
+/// score under [`Options`]. This is the refinement the CLI runs on its screened frames.
+///
+/// The frame has the columns of [`join_overlaps`], the optional ones the options read, and
+/// one row per read and candidate region. Every frame is lazy, nothing runs until collected.
+pub struct Disambiguation {
+    /// Every candidate left by the candidate and read filters of the options
+    pub candidates: LazyFrame,
+    /// The reads with a single candidate
+    pub unique: LazyFrame,
+    /// Every candidate of the reads with several
+    pub ambiguous: LazyFrame,
+    /// The candidates of `ambiguous` that can be scored, none of their fields is missing
+    pub scorable: LazyFrame,
+    /// The winning candidate of every ambiguous read the tie-break keeps
+    pub resolved: LazyFrame,
+    opts: Options,
+}
+
+impl Disambiguation {
+    /// Fails if a column the options read is missing, or the 3' window is below 1 base.
+    pub fn new(joined: LazyFrame, opts: &Options) -> PolarsResult<Disambiguation> {
+        let metric = opts.metric;
+        let three_prime = opts.three_prime;
+        if three_prime.is_some_and(|window| window < 1) {
+            return Err(PolarsError::ComputeError("the 3' window needs at least 1 base".into()));
+        }
+        let mut candidates = joined;
+        // candidates whose 3' window misses the region, or that fail the absolute and
+        // fraction thresholds, are dropped before unique reads are picked
+        if three_prime.is_some() || opts.min_absolute_and_fraction.is_some() {
+            let schema = candidates.schema()?;
+            let mut candidate_fields = vec![col("align_0"), col("align_1"), col("region_0"), col("region_1"), col("cigar")];
+            if three_prime.is_some() {
+                candidate_fields.push(col("flag"));
+            }
+            let mut keep = lit(true);
+            if three_prime.is_some() {
+                keep = keep.and(col("candidate_cov").gt(lit(0)));
+            }
+            if let Some((min_bases, min_fraction)) = opts.min_absolute_and_fraction {
+                keep = keep
+                    .and(col("candidate_cov").gt_eq(lit(min_bases)))
+                    .and((col("candidate_cov").cast(DataType::Float64) / (col("region_1") - col("region_0")).cast(DataType::Float64)).gt_eq(lit(min_fraction)));
+            }
+            candidates = candidates
+                .with_column(
+                    as_struct(&candidate_fields)
+                    .map(move |s| {
+                        let ca = s.struct_()?;
+                        let fields = ca.fields();
+                        let ca_flag: Box<dyn Iterator<Item = Option<i64>>> = if three_prime.is_some() {
+                            Box::new(fields[5].i64()?.into_iter())
+                        } else {
+                            Box::new(std::iter::repeat(Some(0)))
+                        };
+                        let cov: Vec<Option<i64>> = fields[0].i64()?.into_iter()
+                            .zip(fields[1].i64()?)
+                            .zip(fields[2].i64()?)
+                            .zip(fields[3].i64()?)
+                            .zip(fields[4].utf8()?)
+                            .zip(ca_flag)
+                            .map(|(((((a, b), c), d), cigar), flag)| match (a, b, c, d, cigar, flag) {
+                                (Some(a), Some(b), Some(c), Some(d), Some(cigar), Some(flag)) => match three_prime {
+                                    Some(window) => three_prime_coverage(a, c, d, cigar, flag & 16 != 0, window, metric),
+                                    None => calc_coverage(a, b, c, d, cigar, metric),
+                                }
+                                    .map(Some)
+                                    .map_err(|e| PolarsError::ComputeError(e.into())),
+                                _ => Ok(None),
+                            })
+                            .collect::<PolarsResult<_>>()?;
+                        Ok(Some(Int64Chunked::from_iter(cov).into_series()))
+                    }, GetOutput::from_type(DataType::Int64))
+                    .alias("candidate_cov")
+                )
+                .filter(keep)
+                .select(schema.iter_names().map(|name| col(name)).collect::<Vec<_>>());
+        }
+        // adapter dimers and heavily trimmed reads overlap by chance, a read left with one
+        // alignment is unique like after a MAPQ filter
+        if let Some(min_length) = opts.min_read_length {
+            let span = col("cigar")
+                .map(|s| {
+                    let span: Int64Chunked = s.utf8()?.into_iter()
+                        .map(|c| c.map(aligned_span).transpose())
+                        .collect::<Result<_, _>>()
+                        .map_err(|e| PolarsError::ComputeError(e.into()))?;
+                    Ok(Some(span.into_series()))
+                }, GetOutput::from_type(DataType::Int64));
+            candidates = candidates.filter(span.gt_eq(lit(min_length)));
+        }
+        let schema = candidates.schema()?;
+
+        let unique = candidates.clone().unique(Some(vec![String::from("read")]), UniqueKeepStrategy::None);
+        let ambiguous = candidates.clone().filter(
+            col("read").is_in(lit(unique.clone().select([col("read")]).collect()?["read"].clone())).not()
+        );
+        // candidates without coordinates cannot be scored, they are dropped before the arg_max
+        // and a read left without candidates stays unassigned
+        let scorable = ambiguous.clone().filter(
+            score_fields(opts).into_iter()
+                .map(|field| field.is_not_null())
+                .reduce(|acc, not_null| acc.and(not_null))
+                .unwrap()
+        );
+        let mut disambiguation = Disambiguation {
+            candidates,
+            unique,
+            ambiguous,
+            scorable,
+            resolved: LazyFrame::default(),
+            opts: opts.clone(),
+        };
+        disambiguation.resolved = if opts.paired { disambiguation.resolve_fragments(&schema) } else { disambiguation.resolve(&schema) };
+        Ok(disambiguation)
+    }
+
+    /// The score the arg_max compares, of every row of a frame with the candidate columns.
+    pub fn score(&self) -> Expr {
+        let score = self.unweighted_score();
+        if self.opts.alignment_weight {
+            score * col("weight")
+        } else {
+            score
+        }
+    }
+
+    /// [`Disambiguation::score`] without the weight of the candidate's alignment.
+    pub fn unweighted_score(&self) -> Expr {
+        as_struct(&score_fields(&self.opts)).map(score_udf(&self.opts), GetOutput::from_type(DataType::Float64))
+    }
+
+    // scored over the whole frame at once, each group only picks its arg_max
+    fn resolve(&self, schema: &Schema) -> LazyFrame {
+        let tie_break = self.opts.tie_break;
+        let on_resolved = self.opts.on_resolved.clone();
+        // the source BED comes last, after the fields break_tie reads
+        let feature_priority = self.opts.feature_priority.clone();
+        let mut pick_fields = vec![col("cov_score"), col("region"), col("region_0"), col("region_1")];
+        if !feature_priority.is_empty() {
+            pick_fields.push(col("source"));
+        }
+        let picked = self.scorable.clone()
+            .with_column(self.score().alias("cov_score"))
+            .groupby_stable([col("read")])
+            .agg([
+                as_struct(&pick_fields)
+                .apply(move |s| {
+                    let fields = s.struct_()?.fields().to_vec();
+                    let mut scores = fields[0].f64()?.clone();
+                    if !feature_priority.is_empty() {
+                        scores = prefer_sources(&scores, fields[fields.len() - 1].utf8()?, &feature_priority);
+                    }
+                    let idx = break_tie(
+                        &scores,
+                        fields[1].utf8()?,
+                        fields[2].i64()?,
+                        fields[3].i64()?,
+                        tie_break,
+                    );
+                    if let Some(on_resolved) = on_resolved.as_deref() {
+                        on_resolved();
+                    }
+                    Ok(Some(Series::new("cov_idx", &[idx])))
+                }, GetOutput::from_type(IDX_DTYPE))
+                .first().alias("cov_idx"),
+            ])
+            .filter(col("cov_idx").is_not_null());
+        let resolved = self.scorable.clone()
+            .join(picked, [col("read")], [col("read")], JoinType::Inner)
+            .groupby_stable([col("read")])
+            .agg([
+                col("*").exclude(["cov_idx"]).take(col("cov_idx")).first()
+            ]);
+        with_schema(resolved, schema)
+    }
+
+    // a mate is told apart from the other by its FLAG; it counts with its best interval of each region
+    // name, the name with the highest sum over the mates wins and each mate keeps its best interval of it
+    fn resolve_fragments(&self, schema: &Schema) -> LazyFrame {
+        let tie_break = self.opts.tie_break;
+        let scored = self.scorable.clone().with_column(self.score().alias("score"));
+        let totals = scored.clone()
+            .groupby_stable([col("read"), col("read_name"), col("flag"), col("region")])
+            .agg([col("score").max(), col("region_0").min(), col("region_1").max()])
+            .groupby_stable([col("read"), col("region")])
+            .agg([col("score").sum(), col("region_0").min(), col("region_1").max()]);
+        let region_idx = totals.clone()
+            .groupby_stable([col("read")])
+            .agg([
+                as_struct(&[col("score"), col("region"), col("region_0"), col("region_1")])
+                .apply(move |s| {
+                    let fields = s.struct_()?.fields().to_vec();
+                    let idx = break_tie(fields[0].f64()?, fields[1].utf8()?, fields[2].i64()?, fields[3].i64()?, tie_break);
+                    Ok(Some(Series::new("region_idx", &[idx])))
+                }, GetOutput::from_type(IDX_DTYPE))
+                .first().alias("region_idx"),
+            ])
+            .filter(col("region_idx").is_not_null());
+        let winners = totals
+            .join(region_idx, [col("read")], [col("read")], JoinType::Inner)
+            .groupby_stable([col("read")])
+            .agg([col("region").take(col("region_idx")).first()]);
+        let resolved = scored
+            .join(winners, [col("read"), col("region")], [col("read"), col("region")], JoinType::Inner)
+            .sort_by_exprs([col("score")], [true], false)
+            .unique_stable(Some(vec!["read_name".to_string(), "flag".to_string()]), UniqueKeepStrategy::First);
+        with_schema(resolved, schema)
+    }
+
+    /// `lf` without the reads whose best plain coverage among `candidates` is below the
+    /// `min_coverage` of the options, so an ambiguous read below the threshold is dropped
+    /// instead of going to its strongest weak region.
+    pub fn strong(&self, lf: LazyFrame) -> PolarsResult<LazyFrame> {
+        let Some(min_coverage) = self.opts.min_coverage else {
+            return Ok(lf);
+        };
+        let weak = self.candidates.clone()
+            .with_column(coverage_column(self.opts.metric).alias("best_cov"))
+            .groupby([col("read")])
+            .agg([col("best_cov").max()])
+            .filter(col("best_cov").fill_null(lit(0)).lt(lit(min_coverage)))
+            .collect()?;
+        Ok(lf.filter(col("read").is_in(lit(weak["read"].clone())).not()))
+    }
+
+    /// The assigned candidates over `min_coverage`: the unique reads, then the ambiguous ones
+    /// in their own order, each with its winning region or, under `no_resolve`, all scorable
+    /// candidates. Under `top_k` the best k candidates of every read instead, scored in a
+    /// `coverage` column and ranked from 1 in a `rank` column.
+    pub fn assigned(&self) -> PolarsResult<LazyFrame> {
+        if let Some(k) = self.opts.top_k {
+            // every candidate is scored on its own, unique reads included
+            return Ok(self.strong(self.candidates.clone())?
+                .filter(
+                    score_fields(&self.opts).into_iter()
+                        .map(|field| field.is_not_null())
+                        .reduce(|acc, not_null| acc.and(not_null))
+                        .unwrap()
+                )
+                .with_column(self.score().alias("coverage"))
+                .with_column(
+                    col("coverage")
+                        .rank(RankOptions { method: RankMethod::Ordinal, descending: true }, None)
+                        .over([col("read")])
+                        .alias("rank")
+                )
+                .filter(col("rank").lt_eq(lit(k))));
+        }
+        let ambiguous = if self.opts.no_resolve { self.scorable.clone() } else { self.resolved.clone() };
+        // not parallel, so the unique reads come before the ambiguous ones in their own order
+        concat(&[self.strong(self.unique.clone())?, self.strong(ambiguous)?], false, false)
+    }
+}
+
+/// Assigns every read to the region it covers best, the CLI's refinement of its screened
+/// frames on in-memory dataframes: [`join_overlaps`] of every BED file, then the arg_max of
+/// [`Disambiguation`].
+///
+/// `bam_df` holds the SAM records and `bed_dfs` the intersect output of each BED file,
+/// laid out as described in [`join_overlaps`]. The result has the rows of
+/// [`Disambiguation::assigned`], unique reads first, with the [`join_overlaps`] columns and
+/// the plain `coverage` of the candidate under the metric of the options.
+///
+/// ```
+/// use polars::prelude::*;
+/// use locreadion::{Options, disambiguate_reads};
+///
+/// # fn main() -> PolarsResult<()> {
+/// let bam_df = df!(
+///     "column_1" => &["r1"],
+///     "column_3" => &["chr1"],
+///     "column_4" => &[101i64],
+///     "column_6" => &["50M"],
+/// )?;
+/// let region = |start: i64, end: i64, name: &str| df!(
+///     "column_1" => &["chr1"],
+///     "column_2" => &[100i64],
+///     "column_3" => &[150i64],
+///     "column_4" => &["r1"],
+///     "column_14" => &[start],
+///     "column_15" => &[end],
+///     "column_16" => &[name],
+/// );
+/// let bed_dfs = vec![region(90, 125, "B1")?, region(120, 200, "A1")?];
+/// let result = disambiguate_reads(bam_df.clone(), bed_dfs.clone(), &Options::default())?;
+/// assert_eq!(result["region"].utf8()?.get(0), Some("A1"));
+/// assert_eq!(result["coverage"].i64()?.get(0), Some(30));
+/// // B1 is the shorter region and fills more of it
+/// let fraction = Options { fraction: true, ..Options::default() };
+/// let result = disambiguate_reads(bam_df.clone(), bed_dfs.clone(), &fraction)?;
+/// assert_eq!(result["region"].utf8()?.get(0), Some("B1"));
+/// // the threshold keeps a best coverage equal to it
+/// let at = Options { min_coverage: Some(30), ..Options::default() };
+/// assert_eq!(disambiguate_reads(bam_df.clone(), bed_dfs.clone(), &at)?.height(), 1);
+/// let strict = Options { min_coverage: Some(31), ..Options::default() };
+/// assert_eq!(disambiguate_reads(bam_df, bed_dfs, &strict)?.height(), 0);
+/// # Ok(())
+/// # }
+/// ```
+pub fn disambiguate_reads(bam_df: DataFrame, bed_dfs: Vec<DataFrame>, opts: &Options) -> PolarsResult<DataFrame> {
+    let joined: Vec<LazyFrame> = bed_dfs.into_iter()
+        .map(|bed_df| join_overlaps(bed_df, bam_df.clone(), opts.join_strategy))
+        .collect();
+    let disambiguation = Disambiguation::new(concat(&joined, false, true)?, opts)?;
+    disambiguation.assigned()?
+        .with_column(coverage_column(opts.metric).alias("coverage"))
+        .collect()
+}
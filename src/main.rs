@@ -1,320 +1,2804 @@
 
+use std::ffi::OsString;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio, exit};
-use std::io::Cursor;
-use std::collections::HashMap;
+use std::process::{Child, Command, ExitStatus, Stdio, exit};
+use std::io::{Cursor, IsTerminal, Read, Write};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 use polars::lazy::dsl::GetOutput;
 use polars::prelude::*;
-use clap::Parser;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap::parser::ValueSource;
+use flate2::read::MultiGzDecoder;
+use tempfile::TempDir;
+use rayon::prelude::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{Level, LevelFilter, debug, error, info, log_enabled, warn};
+
+mod annotation;
+mod bgzf;
+mod cache;
+mod error;
+#[cfg(feature = "native")]
+mod native;
+
+use annotation::FeatureType;
+use bgzf::BgzfWriter;
+use cache::CacheEntry;
+use error::{ReadlocError, ReadlocResult};
+
+// reports asked for on the command line go to stdout, coloured only when it is a terminal
+macro_rules! report {
+    ($($arg:tt)*) => {
+        println!("{}", colored(std::io::stdout().is_terminal(), format!($($arg)*)))
+    };
+}
+use locreadion::{Assignments, CoordBase, CoverageMetric, Disambiguation, JoinStrategy, Options, SAM_POS_OFFSET, TieBreak, WeightTrack, aligned_span, calc_coverage, check_join, cigar_parser, exclude_reads, join_overlaps, keep_regions, merge_range, shift_starts, timing_table, unsplit_cigar};
 
 #[derive(Parser)]
 #[command(name = "readloc")]
 #[command(about = "Remove region ambiguity for reads", long_about = None)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Cli {
-    #[arg(short, value_name = "ALIGNED_BAM", help = "Aligned BAM files")]
-    a: PathBuf,
-    #[arg(short, value_name = "REGION_BED_DIR", help = "BED files generated from gtf annotations")]
-    r: PathBuf,
+    #[arg(short, value_name = "ALIGNED_BAM", num_args = 1.., required_unless_present = "sample_manifest", help = "Aligned BAM or CRAM files, each written to its own output named by its file stem, or - to read one from stdin")]
+    a: Vec<PathBuf>,
+    #[arg(short, value_name = "REGION_BED_DIR", required_unless_present = "sample_manifest", help = "BED files generated from gtf annotations, a .gtf or .gff3 annotation to convert, or - to read one BED stream from stdin")]
+    r: Option<PathBuf>,
     #[arg(short, value_name = "OUTPUT", help = "Output directory")]
     o: Option<PathBuf>,
+    #[arg(long, value_name = "N", conflicts_with = "top_k", help = "Drop reads assigned to regions with fewer than N assigned reads")]
+    min_region_reads: Option<u32>,
+    #[arg(long, value_name = "DIR", help = "Write each BED file's joined frame (pre-dedup) to parquet in DIR")]
+    dump_intermediate: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = JoinStrategy::Auto, help = "Evaluation strategy for the BED-BAM join")]
+    join_strategy: JoinStrategy,
+    #[arg(long, help = "Report overlapping, unique, disambiguated and lost reads and the seconds spent per BED file")]
+    stats_per_bed: bool,
+    #[arg(long, value_enum, default_value_t = CoverageMetric::Total, help = "Per-region coverage used to pick the region of ambiguous reads")]
+    coverage_metric: CoverageMetric,
+    #[arg(long, help = "Replace whitespace in read names with '_' instead of failing")]
+    sanitize_names: bool,
+    #[arg(long, conflicts_with = "top_k", help = "Keep a single read per region and alignment start")]
+    collapse_position: bool,
+    #[arg(long, value_name = "SECONDS", help = "Kill the running intersects and start no new BED file after SECONDS, then write partial results")]
+    timeout_total: Option<u64>,
+    #[arg(long, value_name = "FILE", help = "Two-column TSV of region and prior weight multiplied into the coverage")]
+    prior: Option<PathBuf>,
+    #[arg(long, help = "Compress the output with BGZF and index it with tabix")]
+    tabix: bool,
+    #[arg(long, help = "Add a span column with the aligned reference bases of each read, introns excluded")]
+    emit_span: bool,
+    #[arg(long, value_name = "TSV", conflicts_with_all = ["a", "r"], help = "TSV of bam, region_dir and output_name, one sample per line")]
+    sample_manifest: Option<PathBuf>,
+    #[arg(long, help = "Report overlapping region definitions in each BED file before screening reads")]
+    annotation_qc: bool,
+    #[arg(long, conflicts_with_all = ["top_k", "no_resolve"], help = "Write per-region read counts with counts per million next to the output")]
+    cpm: bool,
+    #[arg(long, hide = true, help = "Run the assignment twice and fail unless the outputs are identical")]
+    self_check_determinism: bool,
+    #[arg(long, help = "Weight coverage by the base qualities of the overlapping bases (slower, carries QUAL per record)")]
+    qual_weight: bool,
+    #[arg(long, value_name = "BEDGRAPH", conflicts_with = "qual_weight", help = "Weight every overlapping base by a per-base track such as mappability or GC (one lookup per block)")]
+    weight_track: Option<PathBuf>,
+    #[arg(long, value_name = "SECONDS", help = "Skip a BED file whose intersect takes longer than SECONDS")]
+    per_bed_timeout: Option<u64>,
+    #[arg(long, help = "Also write one output file per assigned region into <OUTPUT>/<name>.regions/")]
+    partition_by_region: bool,
+    #[arg(long, value_name = "N", default_value_t = 10000, help = "Refuse to write more than N region partitions")]
+    max_partitions: usize,
+    #[arg(long, value_enum, help = "Sequencing platform used to choose defaults for unset options")]
+    platform: Option<Platform>,
+    #[arg(long, help = "Output every mapped read of the BAM, with empty region fields and a reason for unassigned ones")]
+    include_unassigned: bool,
+    #[arg(long, help = "Add the BED file each assigned region came from as a column")]
+    emit_source: bool,
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "1", conflicts_with_all = ["qual_weight", "weight_track"], help = "Only count overlap of the last N aligned bases at the read's 3' end (default 1), for 3'-tag protocols")]
+    three_prime_only: Option<i64>,
+    #[arg(long, value_name = "BASES,FRACTION", value_parser = parse_absolute_and_fraction, help = "Only let a read compete for a region it covers by at least BASES bases and FRACTION of the region length")]
+    min_absolute_and_fraction: Option<(i64, f64)>,
+    #[arg(long, value_name = "FILE", conflicts_with = "sample_manifest", help = "Write the annotated regions that received no assigned read to FILE as BED")]
+    empty_regions: Option<PathBuf>,
+    #[arg(long, value_name = "BYTES", default_value_t = 1 << 20, value_parser = clap::value_parser!(u64).range(1..), help = "Bytes read from the intersect output per read call")]
+    stream_batch_bytes: u64,
+    #[arg(long, help = "Also write fractional per-region counts from an EM over the ambiguous reads to <OUTPUT>/<name>.em.tsv")]
+    em: bool,
+    #[arg(long, value_name = "N", default_value_t = 1000, requires = "em", help = "Stop the EM after N iterations")]
+    em_iters: usize,
+    #[arg(long, value_name = "TOL", default_value_t = 1e-6, requires = "em", help = "Stop the EM once no region abundance changes by more than TOL")]
+    em_tol: f64,
+    #[arg(long, value_name = "SEED", requires = "em", help = "Start the EM from random abundances drawn with SEED instead of uniform ones")]
+    seed: Option<u64>,
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..), help = "Output the N best candidate regions of every read with their coverage and rank")]
+    top_k: Option<u32>,
+    #[arg(long, value_name = "DIR", help = "Directory for intermediate files such as a buffered stdin BED (default: system temp dir)")]
+    tmp_dir: Option<PathBuf>,
+    #[arg(long, value_name = "BED", help = "Compare the assignments with a ground truth in the output layout (read in column 4, region in column 7)")]
+    truth: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", help = "TSV of contig name and sort rank for the output order (default: human chr1-chr22, chrX, chrY, chrM)")]
+    chrom_order: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = Engine::Bedtools, help = "How reads are intersected with the regions")]
+    engine: Engine,
+    #[arg(long, value_name = "N", help = "Intersect at most N BED files at once (default: one per CPU)")]
+    threads: Option<usize>,
+    #[arg(long, value_enum, default_value_t = TieBreak::First, help = "Which region an ambiguous read goes to when several have its best coverage")]
+    tie_break: TieBreak,
+    #[arg(long, help = "Add the coverage of the assigned region as a column, scored as in the disambiguation")]
+    with_coverage: bool,
+    #[arg(long, value_name = "INT", help = "Drop reads whose best coverage over their candidate regions is below INT bases")]
+    min_coverage: Option<i64>,
+    #[arg(long, value_name = "FASTA", help = "Reference FASTA the CRAM input was compressed against, ignored for BAM")]
+    reference: Option<PathBuf>,
+    #[arg(long, value_name = "INT", help = "Drop alignments with a MAPQ below INT before they compete for regions")]
+    min_mapq: Option<u8>,
+    #[arg(long, value_name = "NAME", help = "Output name of the alignment read from stdin with -a -")]
+    name: Option<String>,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Bed, help = "Format of the per-read output, also its file extension after .reloc")]
+    format: OutputFormat,
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["sample_manifest", "top_k", "no_resolve"], help = "Write the number of assigned reads per region to FILE, with region length and counts per kb under --with-coverage")]
+    counts: Option<PathBuf>,
+    #[arg(long, help = "Log no status lines or progress bars, only errors and warnings; requested reports still print")]
+    quiet: bool,
+    #[arg(long, value_enum, default_value_t = Strand::Same, help = "Which strand a read must be on to overlap a region")]
+    strand: Strand,
+    #[arg(long, value_enum, default_value_t = Metric::Coverage, help = "Score ambiguous reads by covered bases or by the covered fraction of the region")]
+    metric: Metric,
+    #[arg(long, value_name = "TOML", help = "Read options from a TOML file of long option names, options given on the command line win")]
+    config: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", conflicts_with = "sample_manifest", help = "Write every candidate region of the ambiguous reads with its score to FILE, before one is picked")]
+    report_candidates: Option<PathBuf>,
+    #[arg(long, conflicts_with = "top_k", help = "Output every candidate region of the ambiguous reads instead of the best one")]
+    no_resolve: bool,
+    #[arg(long, help = "Drop secondary and supplementary alignments (FLAG 0x100 and 0x800) before the overlap")]
+    primary_only: bool,
+    #[arg(long, value_enum, default_value_t = FeatureType::Exon, help = "Features of a -r annotation that become regions, named by their gene, transcript or exon ID")]
+    feature_type: FeatureType,
+    #[arg(long, value_name = "FILE", conflicts_with = "sample_manifest", help = "Also write the read disposition counts as JSON to FILE")]
+    summary: Option<PathBuf>,
+    #[arg(long, value_name = "PATH", env = "BEDTOOLS", default_value = "bedtools", help = "bedtools executable, a name looked up on PATH or a path")]
+    bedtools: PathBuf,
+    #[arg(long, value_name = "PATH", env = "SAMTOOLS", default_value = "samtools", help = "samtools executable, a name looked up on PATH or a path")]
+    samtools: PathBuf,
+    #[arg(long, value_name = "DIR", help = "Write the intersect output of each BED file to DIR and parse it from there instead of from memory")]
+    keep_temp: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = ReadKey::Name, help = "What makes alignments the same read when they compete for regions")]
+    read_key: ReadKey,
+    #[arg(long, conflicts_with_all = ["read_key", "top_k", "no_resolve"], help = "Resolve paired-end fragments: mates named <name>/1 and <name>/2 or sharing a name go together to the region of their highest summed coverage")]
+    paired: bool,
+    #[arg(long, help = "Check the tools and inputs and print what would be processed, without intersecting anything")]
+    dry_run: bool,
+    #[arg(long, help = "Write the BED or JSON lines output BGZF compressed, to .reloc.<format>.gz")]
+    compress: bool,
+    #[arg(long, value_name = "BEDS", value_delimiter = ',', conflicts_with = "paired", help = "Comma-separated BED file names without extension, e.g. exon,CDS,UTR,gene; among candidates tied on the best coverage the region from the earliest listed file wins, before --tie-break")]
+    feature_priority: Vec<String>,
+    #[arg(long, value_name = "INT", help = "Drop alignments with fewer than INT aligned reference bases, introns excluded, before reads compete for regions")]
+    min_read_length: Option<i64>,
+    #[arg(long, value_enum, default_value_t = CoordBase::Zero, help = "Base of the alignment and region starts written out, 0 for BED-style half-open or 1 for closed intervals; ends are the same under both")]
+    coord_base: CoordBase,
+    #[arg(long, value_name = "DIR", conflicts_with = "keep_temp", help = "Store the intersect output of each alignment and BED file in DIR and reuse it while both files and the intersect options are unchanged")]
+    cache_dir: Option<PathBuf>,
+    #[arg(long, help = "Intersect afresh without reading or writing --cache-dir, e.g. when it is set in the config file")]
+    no_cache: bool,
+    #[arg(long, help = "Leave the read strand column out of the output, for the column set of earlier versions")]
+    no_strand: bool,
+    #[arg(long, value_name = "TAG", value_parser = parse_sam_tag, conflicts_with = "top_k", help = "SAM tag holding the UMI, e.g. UB; assigned reads with the same UMI, region and alignment start are counted once")]
+    umi_tag: Option<String>,
+    #[arg(long, value_name = "FILE", conflicts_with = "sample_manifest", help = "Write the mapped reads of the BAM that overlap no region to FILE, one line per read with its first alignment")]
+    unassigned: Option<PathBuf>,
+    #[arg(long, help = "Prefix every region name with its BED file name, e.g. exon:TP53, so regions of different files sharing a name stay apart")]
+    namespace_regions: bool,
+    #[arg(long, help = "Overlap and cover regions with the whole alignment span, introns included, instead of the aligned blocks; for DNA assays such as ChIP-seq")]
+    no_split: bool,
+    #[arg(short = 'V', long, help = "Print the version of readloc and of the bedtools and samtools it would use, then exit")]
+    version: bool,
+    #[arg(short = 'v', long, action = clap::ArgAction::Count, conflicts_with = "quiet", help = "Also log the bedtools and samtools commands run, twice for every debug detail")]
+    verbose: u8,
+    #[arg(long, value_name = "FILE", help = "Leave out the reads named in FILE, one per line, from every output, counts and reports included")]
+    exclude_reads: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", help = "Only assign reads to the regions named in FILE, one per line, and leave the rest of the annotation out")]
+    regions: Option<PathBuf>,
+    #[arg(long, value_name = "DIR", help = "Keep each BED file's joined frame in DIR as it completes, and reuse it instead of screening the file again on a rerun")]
+    checkpoint_dir: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = Weight::None, help = "Weight the score of each candidate by the confidence of its alignment, so multi-mapped reads lean to their better alignments")]
+    weight: Weight,
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+impl Cli {
+    // the SAM FLAG is only read when an option looks at it
+    fn needs_flag(&self) -> bool {
+        self.three_prime_only.is_some() || self.read_key == ReadKey::Mate || self.paired
+    }
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Join per-sample region counts into one region x sample count matrix
+    Merge(MergeArgs),
+    /// Report region counts, coverage and assignment statistics of an existing .reloc.bed
+    Summarize(SummarizeArgs),
+    /// Merge the overlapping intervals of each region name and strand of a BED file
+    MergeBed(MergeBedArgs),
+}
+
+#[derive(Args)]
+struct MergeArgs {
+    #[arg(value_name = "COUNTS_TSV", required = true, help = "Region counts written by --cpm, the sample name is the file name without .counts.tsv")]
+    counts: Vec<PathBuf>,
+    #[arg(short, value_name = "MATRIX", help = "Output matrix, written as parquet if it ends in .parquet and as TSV otherwise")]
+    o: PathBuf,
+}
+
+#[derive(Args)]
+struct SummarizeArgs {
+    #[arg(value_name = "RELOC_BED", help = "Output of a previous run")]
+    reloc: PathBuf,
+    #[arg(short, value_name = "COUNTS_TSV", help = "Write the region counts to COUNTS_TSV instead of printing them")]
+    o: Option<PathBuf>,
+    #[arg(long, value_enum, default_value_t = CoverageMetric::Total, help = "How the overlap of a read with its region is measured")]
+    coverage_metric: CoverageMetric,
+}
+
+#[derive(Args)]
+struct MergeBedArgs {
+    #[arg(value_name = "IN_BED", help = "Region file, .bed or .bed.gz")]
+    input: PathBuf,
+    #[arg(value_name = "OUT_BED", help = "Merged region file")]
+    output: PathBuf,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Platform {
+    /// Short reads (Illumina)
+    Short,
+    /// Long reads (ONT, PacBio) with long CIGAR strings
+    Long,
+}
+
+struct Region {
+    chr: String,
+    start: i64,
+    end: i64,
+    name: String,
+    strand: String,
+}
+
+struct Sample {
+    align_file: PathBuf,
+    region_dir: PathBuf,
+    name: String,
+}
+
+// both intersects report the same alignments, any loss beyond a stray record means the coordinates disagree
+const MAX_UNMATCHED_FRACTION: f64 = 0.01;
+
+// same code as coreutils `timeout`, so schedulers and scripts can tell it apart
+const EXIT_TIMEOUT: i32 = 124;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Header-less tab-separated table
+    Bed,
+    /// One JSON object per row with named fields
+    Jsonl,
+    /// Typed columns in a parquet file
+    Parquet,
+}
+
+// what the arg_max compares, on top of the --coverage-metric
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Metric {
+    /// Covered bases, favours long regions overlapped by chance
+    Coverage,
+    /// Covered bases over the region length, favours the region the read fills best
+    Fraction,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Strand {
+    /// Reads on the strand of the region, bedtools -s
+    Same,
+    /// Reads on the other strand, bedtools -S
+    Opposite,
+    /// Reads on either strand, for unstranded libraries
+    Ignore,
+}
+
+impl Strand {
+    fn bedtools_flag(self) -> Option<&'static str> {
+        match self {
+            Strand::Same => Some("-s"),
+            Strand::Opposite => Some("-S"),
+            Strand::Ignore => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Weight {
+    /// Score candidates by their coverage alone
+    None,
+    /// Multiply the score of a candidate by the MAPQ of its alignment, 255 (unavailable) counts as 0
+    Mapq,
+    /// Multiply the score of a candidate by the AS tag of its alignment, missing or negative scores count as 0
+    As,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ReadKey {
+    /// The read name, so every alignment of a name is one read (the previous behaviour)
+    Name,
+    /// The read name and the first/second-in-pair bits of the FLAG, so the mates of a pair are separate reads
+    Mate,
+    /// The read name, chromosome and start, so every alignment is a read of its own
+    Alignment,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Engine {
+    /// bedtools intersect and samtools view
+    Bedtools,
+    /// Read the BAM with rust-htslib and intersect in process (needs the `native` feature)
+    Native,
+}
+
+fn parse_absolute_and_fraction(value: &str) -> Result<(i64, f64), String> {
+    let (bases, fraction) = value.split_once(',')
+        .ok_or_else(|| "expected BASES,FRACTION, e.g. 10,0.2".to_string())?;
+    let bases = bases.trim().parse::<i64>().map_err(|e| format!("invalid BASES: {}", e))?;
+    let fraction = fraction.trim().parse::<f64>().map_err(|e| format!("invalid FRACTION: {}", e))?;
+    if bases < 0 || !(0.0..=1.0).contains(&fraction) {
+        return Err("BASES must not be negative and FRACTION must be within 0 and 1".to_string());
+    }
+    Ok((bases, fraction))
+}
+
+// two characters, a letter and then a letter or digit, as the SAM spec defines tag names
+fn parse_sam_tag(value: &str) -> Result<String, String> {
+    match value.as_bytes() {
+        [first, second] if first.is_ascii_alphabetic() && second.is_ascii_alphanumeric() => Ok(value.to_string()),
+        _ => Err("expected a two-character SAM tag such as UB or RX".to_string()),
+    }
+}
+
+// reads are attributed to the BED file (`source`) of the region they overlap or won
+fn per_bed_stats(merged: &LazyFrame, uniq: &LazyFrame, duplicated: &LazyFrame, dedup: &LazyFrame) -> PolarsResult<DataFrame> {
+    let overlapping = merged.clone()
+        .groupby([col("source")])
+        .agg([col("read").n_unique().alias("overlapping")]);
+    let unique = uniq.clone()
+        .groupby([col("source")])
+        .agg([col("read").count().alias("unique")]);
+    let disambiguated = dedup.clone()
+        .groupby([col("source")])
+        .agg([col("read").count().alias("disambiguated")]);
+    let lost = duplicated.clone()
+        .select([col("read"), col("source")])
+        .unique(None, UniqueKeepStrategy::First)
+        .join(dedup.clone().select([col("read"), col("source").alias("winner")]), 
+            [col("read")], [col("read")], JoinType::Inner)
+        .filter(col("source").neq(col("winner")))
+        .groupby([col("source")])
+        .agg([col("read").count().alias("lost")]);
+
+    overlapping
+        .join(unique, [col("source")], [col("source")], JoinType::Left)
+        .join(disambiguated, [col("source")], [col("source")], JoinType::Left)
+        .join(lost, [col("source")], [col("source")], JoinType::Left)
+        .with_columns([
+            col("unique").fill_null(lit(0)),
+            col("disambiguated").fill_null(lit(0)),
+            col("lost").fill_null(lit(0)),
+        ])
+        .sort("source", SortOptions::default())
+        .collect()
+}
+
+// read counts of each step of the refinement, before --collapse-position and --min-region-reads
+#[derive(Default)]
+struct Disposition {
+    total_reads: usize,
+    unique: usize,
+    ambiguous: usize,
+    resolved: usize,
+    unresolved: usize,
+    dropped_by_threshold: usize,
+    skipped_parse_error: usize,
+}
+
+impl Disposition {
+    fn report(&self, summary_file: Option<&Path>) -> ReadlocResult<()> {
+        let counts = [
+            ("total_reads", self.total_reads),
+            ("unique", self.unique),
+            ("ambiguous", self.ambiguous),
+            ("resolved", self.resolved),
+            ("unresolved", self.unresolved),
+            ("dropped_by_threshold", self.dropped_by_threshold),
+            ("skipped_parse_error", self.skipped_parse_error),
+        ];
+        info!("Reads: {}", counts.iter().map(|(key, n)| format!("{} {}", key, n)).collect::<Vec<_>>().join(", "));
+        if let Some(summary_file) = summary_file {
+            let json = counts.iter().map(|(key, n)| format!("\"{}\": {}", key, n)).collect::<Vec<_>>().join(", ");
+            fs::write(summary_file, format!("{{{}}}\n", json))
+                .map_err(|source| ReadlocError::Io { op: "write", path: summary_file.to_path_buf(), source })?;
+            info!("Read summary in \x1b[33m{}\x1b[m", summary_file.to_string_lossy());
+        }
+        Ok(())
+    }
+}
+
+fn n_reads(lf: &LazyFrame) -> PolarsResult<usize> {
+    let n = lf.clone()
+        .select([col("read").n_unique().cast(DataType::UInt64).alias("n")])
+        .collect()?;
+    Ok(n["n"].u64()?.get(0).unwrap_or(0) as usize)
+}
+
+// SAM forbids whitespace in QNAME, such names break the tab-delimited parsing and the read grouping
+fn check_read_names(df: &mut DataFrame, name_col: &str, sanitize: bool, file_name: &str) -> ReadlocResult<()> {
+    let names = df.column(name_col)?.utf8()?.clone();
+    if let Some(bad) = names.into_iter().flatten().find(|n| n.contains(char::is_whitespace)) {
+        if !sanitize {
+            return Err(ReadlocError::Bed {
+                op: "parse the read names overlapping",
+                bed: file_name.to_string(),
+                reason: format!("read name {:?} contains whitespace, use --sanitize-names to replace it", bad),
+            });
+        }
+        let mut sanitized: Series = names.into_iter()
+            .map(|n| n.map(|n| n.replace(char::is_whitespace, "_")))
+            .collect::<Utf8Chunked>()
+            .into_series();
+        sanitized.rename(name_col);
+        df.with_column(sanitized)?;
+    }
+    Ok(())
+}
+
+// one name per line for --exclude-reads and --regions, blank lines are skipped and names are matched
+// exactly, including any /1 or /2 suffix of a read
+fn read_name_list(path: &Path) -> ReadlocResult<Vec<String>> {
+    let content = fs::read_to_string(path).map_err(|source| ReadlocError::Io { op: "read", path: path.to_path_buf(), source })?;
+    Ok(content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+// regions missing from the prior keep a weight of 1
+fn read_prior(path: &Path) -> ReadlocResult<HashMap<String, f64>> {
+    let content = fs::read_to_string(path).map_err(|source| ReadlocError::Io { op: "read", path: path.to_path_buf(), source })?;
+    let mut prior = HashMap::new();
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match (fields.first(), fields.get(1).and_then(|w| w.trim().parse::<f64>().ok())) {
+            (Some(region), Some(weight)) if weight >= 0.0 => {
+                prior.insert(region.to_string(), weight);
+            }
+            _ => return Err(ReadlocError::Invalid { what: "prior", path: path.to_path_buf(), reason: format!("line {:?}", line) }),
+        }
+    }
+    Ok(prior)
+}
+
+fn read_weight_track(path: &Path) -> ReadlocResult<WeightTrack> {
+    let content = fs::read_to_string(path).map_err(|source| ReadlocError::Io { op: "read", path: path.to_path_buf(), source })?;
+    WeightTrack::parse(&content).map_err(|reason| ReadlocError::Invalid { what: "weight track", path: path.to_path_buf(), reason })
+}
+
+// two columns, contig name and sort rank
+fn read_chrom_order(path: &Path) -> ReadlocResult<HashMap<String, u32>> {
+    let content = fs::read_to_string(path).map_err(|source| ReadlocError::Io { op: "read", path: path.to_path_buf(), source })?;
+    let mut chr_map = HashMap::new();
+    for line in content.lines().filter(|l| !l.trim().is_empty() && !l.starts_with('#')) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match (fields.first(), fields.get(1).and_then(|r| r.trim().parse::<u32>().ok())) {
+            (Some(chr), Some(rank)) if rank < u32::MAX => {
+                chr_map.insert(chr.to_string(), rank);
+            }
+            _ => return Err(ReadlocError::Invalid { what: "chromosome order", path: path.to_path_buf(), reason: format!("line {:?}", line) }),
+        }
+    }
+    Ok(chr_map)
+}
+
+// the output is already sorted by chromosome and start, as tabix requires
+// `-p bed` is `-s 1 -b 2 -e 3 -0`, 1-based starts are indexed without `-0`
+fn tabix_index(gz_path: &Path, coord_base: CoordBase) -> ReadlocResult<()> {
+    let preset: &[&str] = match coord_base {
+        CoordBase::Zero => &["-p", "bed"],
+        CoordBase::One => &["-s", "1", "-b", "2", "-e", "3"],
+    };
+    let tabix = Command::new("tabix")
+        .arg("-f")
+        .args(preset)
+        .arg(gz_path)
+        .status();
+    match tabix {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(ReadlocError::Io { op: "index", path: gz_path.to_path_buf(), source: std::io::Error::other(format!("tabix exited with {}", status)) }),
+        Err(source) => Err(ReadlocError::Io { op: "index", path: gz_path.to_path_buf(), source }),
+    }
+}
+
+fn write_result(format: OutputFormat, out: &mut impl Write, result: &mut DataFrame) -> PolarsResult<()> {
+    match format {
+        OutputFormat::Bed => CsvWriter::new(out).has_header(false).with_delimiter(b'\t').finish(result),
+        OutputFormat::Jsonl => JsonWriter::new(out).with_json_format(JsonFormat::JsonLines).finish(result),
+        OutputFormat::Parquet => ParquetWriter::new(out).finish(result).map(|_| ()),
+    }
+}
+
+// an optional `bam region_dir output_name` header line is skipped, every path is checked before any work starts
+fn read_manifest(path: &Path) -> ReadlocResult<Vec<Sample>> {
+    let content = fs::read_to_string(path).map_err(|source| ReadlocError::Io { op: "read", path: path.to_path_buf(), source })?;
+    let invalid = |reason: String| ReadlocError::Invalid { what: "sample manifest", path: path.to_path_buf(), reason };
+    let mut samples: Vec<Sample> = vec![];
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() || (i == 0 && line.trim() == "bam\tregion_dir\toutput_name") {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(|f| f.trim()).collect();
+        if fields.len() != 3 || fields.iter().any(|f| f.is_empty()) {
+            return Err(invalid(format!("line {}, expected bam, region_dir and output_name", i + 1)));
+        }
+        let align_file = Path::new(fields[0]).canonicalize()
+            .map_err(|_| invalid(format!("line {}, alignment {} do not exist", i + 1, fields[0])))?;
+        let region_dir = Path::new(fields[1]).canonicalize()
+            .map_err(|_| invalid(format!("line {}, region directory {} do not exist", i + 1, fields[1])))?;
+        if !region_dir.is_dir() {
+            return Err(invalid(format!("line {}, {} is not a directory", i + 1, fields[1])));
+        }
+        if samples.iter().any(|s| s.name == fields[2]) {
+            return Err(invalid(format!("line {}, output name {} is used twice", i + 1, fields[2])));
+        }
+        samples.push(Sample { align_file, region_dir, name: fields[2].to_string() });
+    }
+    if samples.is_empty() {
+        return Err(invalid("it lists no samples".to_string()));
+    }
+    Ok(samples)
+}
+
+// plain or gzipped BED, bedtools reads both as `-b`
+fn is_region_file(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".bed") || name.ends_with(".bed.gz")
+}
+
+// an input checked before it is read, reported like a failed read
+fn not_found(op: &'static str, path: &Path) -> ReadlocError {
+    ReadlocError::Io { op, path: path.to_path_buf(), source: std::io::ErrorKind::NotFound.into() }
+}
+
+fn no_region_files(region_dir: &Path) -> ReadlocError {
+    ReadlocError::Invalid {
+        what: "region directory",
+        path: region_dir.to_path_buf(),
+        reason: "no .bed files, region files must have the .bed or .bed.gz extension".to_string(),
+    }
+}
+
+// plain or gzip compressed text
+fn read_text(path: &Path) -> std::io::Result<String> {
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        // bgzip output is a series of gzip members, a plain decoder would stop after the first
+        fs::File::open(path).and_then(|file| {
+            let mut content = String::new();
+            MultiGzDecoder::new(file).read_to_string(&mut content).map(|_| content)
+        })
+    } else {
+        fs::read_to_string(path)
+    }
+}
+
+fn read_regions(path: &Path) -> ReadlocResult<Vec<Region>> {
+    let content = read_text(path).map_err(|source| ReadlocError::Io { op: "read", path: path.to_path_buf(), source })?;
+    let mut regions = vec![];
+    for line in content.lines() {
+        if line.trim().is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let start = fields.get(1).and_then(|v| v.parse::<i64>().ok());
+        let end = fields.get(2).and_then(|v| v.parse::<i64>().ok());
+        match (start, end) {
+            (Some(start), Some(end)) => regions.push(Region {
+                chr: fields[0].to_string(),
+                start,
+                end,
+                name: fields.get(3).unwrap_or(&".").to_string(),
+                strand: fields.get(5).unwrap_or(&".").to_string(),
+            }),
+            _ => return Err(ReadlocError::Invalid { what: "region file", path: path.to_path_buf(), reason: format!("line {:?}", line) }),
+        }
+    }
+    Ok(regions)
+}
+
+// under -s and -S bedtools compares the sixth column, a region without + or - there never matches
+// and a BED4 shifts it out of the file altogether, so either is reported before anything runs
+fn check_region_strands(strand: Strand, samples: &[Sample]) -> ReadlocResult<()> {
+    if strand == Strand::Ignore {
+        return Ok(());
+    }
+    let mut region_dirs: Vec<&Path> = samples.iter().map(|sample| sample.region_dir.as_path()).collect();
+    region_dirs.dedup();
+    for region_dir in region_dirs {
+        // a missing directory is reported when its sample is screened
+        let Ok(entries) = fs::read_dir(region_dir) else {
+            continue;
+        };
+        let mut bed_files: Vec<PathBuf> = entries.flatten()
+            .map(|entry| entry.path())
+            .filter(|path| is_region_file(path))
+            .collect();
+        bed_files.sort();
+        for bed_file in bed_files {
+            let content = read_text(&bed_file).map_err(|source| ReadlocError::Io { op: "read", path: bed_file.clone(), source })?;
+            let lines = content.lines()
+                .filter(|line| !(line.trim().is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser")));
+            let (mut n_regions, mut n_unstranded, mut first) = (0, 0, None);
+            for (i, line) in lines.enumerate() {
+                n_regions += 1;
+                let fields: Vec<&str> = line.split('\t').collect();
+                if !matches!(fields.get(5), Some(&"+") | Some(&"-")) {
+                    n_unstranded += 1;
+                    first.get_or_insert((i + 1, fields.len()));
+                }
+            }
+            let Some((first_line, n_columns)) = first else {
+                continue;
+            };
+            let flag = strand.to_possible_value().unwrap().get_name().to_string();
+            let reason = if n_columns < 6 {
+                format!("{} columns on region line {}, --strand {} needs the strand in column 6; pass --strand ignore to overlap regardless of strand",
+                    n_columns, first_line, flag)
+            } else {
+                format!("{} of {} regions have no + or - strand, the first on region line {}, and --strand {} would never overlap them; pass --strand ignore to overlap regardless of strand",
+                    n_unstranded, n_regions, first_line, flag)
+            };
+            return Err(ReadlocError::Invalid { what: "region file", path: bed_file, reason });
+        }
+    }
+    Ok(())
+}
+
+// overlaps are half-open, so touching intervals do not count; only regions on the same chromosome and strand are compared
+fn annotation_qc(path: &Path, file_name: &str) -> ReadlocResult<()> {
+    let mut regions = read_regions(path)?;
+    regions.sort_by(|x, y| (&x.chr, &x.strand, x.start).cmp(&(&y.chr, &y.strand, y.start)));
+
+    let mut self_overlaps: HashMap<&str, usize> = HashMap::new();
+    let mut label_overlaps: HashMap<(&str, &str), usize> = HashMap::new();
+    let mut active: Vec<&Region> = vec![];
+    for region in regions.iter() {
+        active.retain(|a| a.chr == region.chr && a.strand == region.strand && a.end > region.start);
+        for a in active.iter() {
+            if a.name == region.name {
+                *self_overlaps.entry(&region.name).or_insert(0) += 1;
+            } else {
+                let pair = if a.name < region.name { (a.name.as_str(), region.name.as_str()) } else { (region.name.as_str(), a.name.as_str()) };
+                *label_overlaps.entry(pair).or_insert(0) += 1;
+            }
+        }
+        active.push(region);
+    }
+
+    report!("\x1b[46mAnnotation QC of {}\x1b[m: {} regions, {} labels with self-overlapping intervals, {} overlapping label pairs", 
+        file_name, regions.len(), self_overlaps.len(), label_overlaps.len());
+    let mut self_list: Vec<(&&str, &usize)> = self_overlaps.iter().collect();
+    self_list.sort();
+    for (label, n) in self_list.iter().take(5) {
+        report!("  {} overlaps itself {} times", label, n);
+    }
+    let mut pair_list: Vec<(&(&str, &str), &usize)> = label_overlaps.iter().collect();
+    pair_list.sort();
+    for ((x, y), n) in pair_list.iter().take(5) {
+        report!("  {} and {} overlap {} times", x, y, n);
+    }
+    Ok(())
+}
+
+// a label can cover several intervals, its length sums the distinct ones reads were assigned to
+fn with_region_length(counts: DataFrame, result: &DataFrame) -> PolarsResult<DataFrame> {
+    let lengths = result.clone().lazy()
+        .filter(col("region").is_not_null())
+        .select([col("region"), col("region_0"), col("region_1")])
+        .unique(None, UniqueKeepStrategy::First)
+        .groupby([col("region")])
+        .agg([(col("region_1") - col("region_0")).sum().alias("length")]);
+    counts.lazy()
+        .join(lengths, [col("region")], [col("region")], JoinType::Left)
+        .with_column(
+            (col("count").cast(DataType::Float64) / (col("length").cast(DataType::Float64) / lit(1e3))).alias("count_per_kb")
+        )
+        .collect()
+}
+
+// library size is the number of reads in the final result
+fn region_counts(result: &DataFrame, cpm: bool) -> PolarsResult<DataFrame> {
+    let assigned = result.clone().lazy().filter(col("region").is_not_null()).collect()?;
+    // reads, not rows, --no-resolve gives an ambiguous read one row per candidate region
+    let library_size = assigned["read"].n_unique()? as f64;
+    let mut counts = assigned.lazy()
+        .groupby([col("region")])
+        .agg([col("read").count().alias("count")]);
+    if cpm {
+        counts = counts.with_column(
+            (col("count").cast(DataType::Float64) / lit(library_size) * lit(1e6)).alias("cpm")
+        );
+    }
+    counts.sort("region", SortOptions::default()).collect()
+}
+
+// regions missing from a sample count 0, columns follow the order of the inputs
+fn merge_counts(args: &MergeArgs) -> ReadlocResult<()> {
+    let mut matrix: Option<LazyFrame> = None;
+    let mut samples: Vec<String> = vec![];
+    for path in args.counts.iter() {
+        if !path.is_file() {
+            return Err(not_found("read", path));
+        }
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        let sample = file_name.strip_suffix(".counts.tsv").unwrap_or(&file_name).to_string();
+        if samples.contains(&sample) {
+            return Err(ReadlocError::Invalid { what: "counts file", path: path.clone(), reason: format!("sample {} appears in more than one counts file", sample) });
+        }
+        let schema = Schema::from_iter([
+            Field::new("region", DataType::Utf8),
+            Field::new("count", DataType::UInt32),
+        ]);
+        let counts = CsvReader::from_path(path)?
+            .with_delimiter(b'\t')
+            .has_header(true)
+            .with_dtypes(Some(Arc::new(schema)))
+            .finish()?
+            .lazy()
+            .select([col("region"), col("count").alias(&sample)]);
+        matrix = Some(match matrix {
+            Some(matrix) => matrix.join(counts, [col("region")], [col("region")], JoinType::Outer),
+            None => counts,
+        });
+        samples.push(sample);
+    }
+    let mut matrix = matrix.unwrap()
+        .with_columns(samples.iter().map(|s| col(s).fill_null(lit(0u32))).collect::<Vec<_>>())
+        .sort("region", SortOptions::default())
+        .collect()?;
+
+    let mut outfile = fs::File::create(&args.o)?;
+    if args.o.extension().is_some_and(|ext| ext == "parquet") {
+        ParquetWriter::new(&mut outfile).finish(&mut matrix)?;
+    } else {
+        CsvWriter::new(&mut outfile).has_header(true).with_delimiter(b'\t').finish(&mut matrix)?;
+    }
+    info!("{} regions x {} samples in \x1b[33m{}\x1b[m", matrix.height(), samples.len(), args.o.to_string_lossy());
+    Ok(())
+}
+
+// only the eight leading columns are read, extra columns such as span, source or reason are ignored
+fn summarize(args: &SummarizeArgs) -> ReadlocResult<()> {
+    if !args.reloc.is_file() {
+        return Err(not_found("read", &args.reloc));
+    }
+    let schema = Schema::from_iter([
+        Field::new("column_4", DataType::Utf8),
+        Field::new("column_7", DataType::Utf8),
+    ]);
+    let mut result = CsvReader::from_path(&args.reloc)?
+        .with_delimiter(b'\t')
+        .has_header(false)
+        .with_projection(Some((0..8).collect()))
+        .with_dtypes(Some(Arc::new(schema)))
+        .finish()?;
+    result.set_column_names(&["chr", "align_0", "align_1", "read", "region_0", "region_1", "region", "cigar"])?;
+
+    let mut coverage: Vec<i64> = vec![];
+    for assignment in Assignments::new(&result, args.coverage_metric)? {
+        let assignment = assignment?;
+        if assignment.region.is_some() {
+            coverage.push(assignment.coverage);
+        }
+    }
+    coverage.sort_unstable();
+    let n_assigned = coverage.len();
+    report!("\x1b[46mSummary of {}\x1b[m", args.reloc.to_string_lossy());
+    report!("Reads: {}, assigned: {}, unassigned: {}", result.height(), n_assigned, result.height() - n_assigned);
+    report!("Regions with reads: {}", result["region"].drop_nulls().n_unique()?);
+    if n_assigned > 0 {
+        let quantile = |q: f64| coverage[((n_assigned - 1) as f64 * q).round() as usize];
+        let mean = coverage.iter().sum::<i64>() as f64 / n_assigned as f64;
+        report!("Coverage (bases): min {}, q1 {}, median {}, q3 {}, max {}, mean {:.2}",
+            quantile(0.0), quantile(0.25), quantile(0.5), quantile(0.75), quantile(1.0), mean);
+    }
+
+    let mut counts = region_counts(&result, true)?;
+    match args.o.as_deref() {
+        Some(counts_file) => {
+            let mut outfile = fs::File::create(counts_file)?;
+            CsvWriter::new(&mut outfile).has_header(true).with_delimiter(b'\t').finish(&mut counts)?;
+            info!("Region counts in \x1b[33m{}\x1b[m", counts_file.to_string_lossy());
+        }
+        None => report!("{:?}", counts),
+    }
+    Ok(())
+}
+
+// intervals are merged within each chromosome, strand and name, touching intervals included like bedtools merge;
+// merged intervals are written sorted by chromosome and start, with score 0
+fn merge_bed(args: &MergeBedArgs) -> ReadlocResult<()> {
+    let mut regions = read_regions(&args.input)?;
+    // merge_range only looks back at the previous interval, so each group is sorted by start
+    regions.sort_by(|x, y| (&x.chr, &x.strand, &x.name, x.start, x.end).cmp(&(&y.chr, &y.strand, &y.name, y.start, y.end)));
+    let mut merged: Vec<(&str, i64, i64, &str, &str)> = vec![];
+    for group in regions.chunk_by(|x, y| (&x.chr, &x.strand, &x.name) == (&y.chr, &y.strand, &y.name)) {
+        let intervals: Vec<(i64, i64)> = group.iter().map(|r| (r.start, r.end)).collect();
+        merged.extend(merge_range(&intervals).into_iter().map(|(start, end)| (group[0].chr.as_str(), start, end, group[0].name.as_str(), group[0].strand.as_str())));
+    }
+    merged.sort_unstable();
+
+    let mut content = String::new();
+    for (chr, start, end, name, strand) in merged.iter() {
+        content.push_str(&format!("{}\t{}\t{}\t{}\t0\t{}\n", chr, start, end, name, strand));
+    }
+    fs::write(&args.output, content).map_err(|source| ReadlocError::Io { op: "write", path: args.output.clone(), source })?;
+    info!("{} intervals merged into {} in \x1b[33m{}\x1b[m", regions.len(), merged.len(), args.output.to_string_lossy());
+    Ok(())
+}
+
+// the annotation becomes a single BED file of a temporary directory, so it goes through the same
+// intersect and coverage as a region directory; the file is named after the annotation
+fn annotation_regions(path: &Path, feature_type: FeatureType, tmp_dir: &Path) -> ReadlocResult<TempDir> {
+    let content = read_text(path).map_err(|source| ReadlocError::Io { op: "read", path: path.to_path_buf(), source })?;
+    let invalid = |reason: String| ReadlocError::Invalid { what: "annotation", path: path.to_path_buf(), reason };
+    let regions = annotation::read_annotation(&content, path, feature_type).map_err(invalid)?;
+    if regions.is_empty() {
+        return Err(invalid(format!("no {} feature", feature_type.gff_types()[0])));
+    }
+    let converted = tempfile::Builder::new()
+        .prefix(&format!("readloc-{}-", std::process::id()))
+        .tempdir_in(tmp_dir)?;
+    let file_name = path.file_name().unwrap().to_string_lossy();
+    let stem = file_name.trim_end_matches(".gz").trim_end_matches(".gtf").trim_end_matches(".gff3").trim_end_matches(".gff");
+    let bed_path = converted.path().join(format!("{}.bed", stem));
+    let mut content = String::new();
+    for region in regions.iter() {
+        content.push_str(&format!("{}\t{}\t{}\t{}\t0\t{}\n", region.chr, region.start, region.end, region.name, region.strand));
+    }
+    fs::write(&bed_path, content).map_err(|source| ReadlocError::Io { op: "write", path: bed_path.clone(), source })?;
+    info!("{} {} regions from \x1b[33m{}\x1b[m", regions.len(), feature_type.gff_types()[0], path.to_string_lossy());
+    Ok(converted)
+}
+
+// bedtools reads its inputs once per intersect, so a stdin stream is buffered as the single file `file_name`
+// of a temporary directory; the directory name is unique per invocation and it is removed when the guard is dropped
+fn buffer_stdin(tmp_dir: &Path, file_name: &str) -> std::io::Result<TempDir> {
+    let buffered = tempfile::Builder::new()
+        .prefix(&format!("readloc-{}-", std::process::id()))
+        .tempdir_in(tmp_dir)?;
+    let mut buffered_file = fs::File::create(buffered.path().join(file_name))?;
+    std::io::copy(&mut std::io::stdin().lock(), &mut buffered_file)?;
+    Ok(buffered)
+}
+
+// inside one of the `readloc-<pid>-` directories of this run, a buffered stream, decoded CRAM or
+// converted annotation gets a new path every run and is never cached
+fn is_run_temporary(path: &Path) -> bool {
+    let prefix = format!("readloc-{}-", std::process::id());
+    path.ancestors().any(|dir| dir.file_name().is_some_and(|name| name.to_string_lossy().starts_with(&prefix)))
+}
+
+// by extension, or by the `CRAM` magic for files named otherwise
+fn is_cram(align_file: &Path) -> bool {
+    if align_file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("cram")) {
+        return true;
+    }
+    let mut magic = [0u8; 4];
+    fs::File::open(align_file)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|_| &magic == b"CRAM")
+}
+
+// bedtools and the native engine get a BAM, so nothing downstream needs to know about CRAM
+fn decode_cram(samtools: &Path, align_file: &Path, reference: &Path, tmp_dir: &Path) -> ReadlocResult<TempDir> {
+    info!("\x1b[44mDecoding CRAM \x1b[33m{}\x1b[m", align_file.to_string_lossy());
+    let decoded = tempfile::Builder::new()
+        .prefix(&format!("readloc-{}-", std::process::id()))
+        .tempdir_in(tmp_dir)?;
+    let status = Command::new(samtools)
+        .args(["view", "-b", "-T"]).arg(reference)
+        .arg("-o").arg(decoded.path().join("decoded.bam"))
+        .arg(align_file)
+        .status()
+        .map_err(|source| ReadlocError::Io { op: "decode", path: align_file.to_path_buf(), source })?;
+    if !status.success() {
+        return Err(ReadlocError::Io {
+            op: "decode",
+            path: align_file.to_path_buf(),
+            source: std::io::Error::other(format!("samtools view exited with {} against {}", status, reference.to_string_lossy())),
+        });
+    }
+    Ok(decoded)
+}
+
+// the build and the external tools a run would use, the bedtools and samtools versions decide
+// the intersect output format; they are only queried here and at the start of a run
+fn print_versions(given: &ArgMatches) {
+    let commit = env!("READLOC_GIT_COMMIT");
+    if commit.is_empty() {
+        report!("readloc {}", env!("CARGO_PKG_VERSION"));
+    } else {
+        report!("readloc {} (commit {})", env!("CARGO_PKG_VERSION"), commit);
+    }
+    for name in ["bedtools", "samtools"] {
+        let cmd = given.get_one::<PathBuf>(name).unwrap();
+        let output = Command::new(cmd).arg("--version").stderr(Stdio::null()).output();
+        match output {
+            Ok(output) if output.status.success() => {
+                let version = String::from_utf8_lossy(&output.stdout);
+                report!("{} at {}", version.lines().next().unwrap_or(name).trim(), cmd.to_string_lossy());
+            }
+            _ => report!("{} not available at {}", name, cmd.to_string_lossy()),
+        }
+    }
+}
+
+// the version line is reported so a pinned --bedtools or --samtools shows up in the log;
+// a bare name is looked up on PATH, anything else must be an executable file
+fn check_command(name: &str, cmd: &Path) -> ReadlocResult<()> {
+    let output = Command::new(cmd)
+        .arg("--version")
+        .stderr(Stdio::null())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            let version = String::from_utf8_lossy(&output.stdout);
+            info!("Using {} at \x1b[33m{}\x1b[m", version.lines().next().unwrap_or(name).trim(), cmd.to_string_lossy());
+            Ok(())
+        }
+        _ if cmd.components().count() == 1 => Err(ReadlocError::Usage(format!("{} is not installed.", cmd.to_string_lossy()))),
+        _ if !cmd.is_file() => Err(ReadlocError::Usage(format!("The {} executable {} do not exist.", name, cmd.to_string_lossy()))),
+        _ => Err(ReadlocError::Usage(format!("The {} executable {} could not be run with --version, check that it is executable.", name, cmd.to_string_lossy()))),
+    }
+}
+
+// the pipe is drained `batch_bytes` at a time, larger batches mean fewer reads and a larger buffer
+fn read_batched<W: Write>(mut stdout: impl Read, batch_bytes: usize, mut out: W) -> std::io::Result<W> {
+    let mut batch = vec![0u8; batch_bytes];
+    loop {
+        match stdout.read(&mut batch) {
+            Ok(0) => {
+                out.flush()?;
+                return Ok(out);
+            }
+            Ok(n) => out.write_all(&batch[..n])?,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// the intersect processes running for the BED files of a run, once the --timeout-total deadline
+// passes they are killed together and no new one is started, so the run stops on its own
+struct Children {
+    deadline: Option<Instant>,
+    // None once the deadline has passed
+    running: Mutex<Option<HashMap<u32, Child>>>,
+}
+
+enum ChildState {
+    Running,
+    Exited(ExitStatus),
+    Killed,
+}
+
+impl Children {
+    fn new(deadline: Option<Instant>) -> Children {
+        Children { deadline, running: Mutex::new(Some(HashMap::new())) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<HashMap<u32, Child>>> {
+        // a poisoned registry still holds the children to kill
+        self.running.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn timed_out(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // None when the deadline has passed, the child is then killed right away
+    fn register(&self, mut child: Child) -> std::io::Result<Option<u32>> {
+        let mut running = self.lock();
+        match running.as_mut() {
+            Some(running) if !self.timed_out() => {
+                let id = child.id();
+                running.insert(id, child);
+                Ok(Some(id))
+            }
+            _ => {
+                drop(running);
+                child.kill()?;
+                child.wait()?;
+                self.kill_all();
+                Ok(None)
+            }
+        }
+    }
+
+    fn state(&self, id: u32) -> std::io::Result<ChildState> {
+        let mut running = self.lock();
+        let Some(child) = running.as_mut().and_then(|running| running.get_mut(&id)) else {
+            return Ok(ChildState::Killed);
+        };
+        Ok(match child.try_wait()? {
+            Some(status) => {
+                running.as_mut().unwrap().remove(&id);
+                ChildState::Exited(status)
+            }
+            None => ChildState::Running,
+        })
+    }
+
+    // None when the child was killed by the deadline
+    fn wait(&self, id: u32) -> std::io::Result<Option<ExitStatus>> {
+        loop {
+            match self.state(id)? {
+                ChildState::Exited(status) => return Ok(Some(status)),
+                ChildState::Killed => return Ok(None),
+                ChildState::Running if self.timed_out() => self.kill_all(),
+                ChildState::Running => std::thread::sleep(Duration::from_millis(20)),
+            }
+        }
+    }
+
+    fn kill(&self, id: u32) -> std::io::Result<()> {
+        let child = self.lock().as_mut().and_then(|running| running.remove(&id));
+        if let Some(mut child) = child {
+            child.kill()?;
+            child.wait()?;
+        }
+        Ok(())
+    }
+
+    fn kill_all(&self) {
+        let Some(running) = self.lock().take() else {
+            return;
+        };
+        if !running.is_empty() {
+            debug!("Killing {} intersect processes at the time limit", running.len());
+        }
+        for (_, mut child) in running {
+            child.kill().ok();
+            child.wait().ok();
+        }
+    }
+}
+
+// copies the stdout of the child to `out`, the child is killed once `deadline` or the deadline
+// of `children` has passed
+fn wait_with_deadline<W: Write + Send + 'static>(children: &Children, mut child: Child, deadline: Option<Instant>, batch_bytes: usize, out: W) -> std::io::Result<Option<W>> {
+    let stdout = child.stdout.take()
+        .ok_or_else(|| std::io::Error::other("child stdout is not piped"))?;
+    let Some(id) = children.register(child)? else {
+        return Ok(None);
+    };
+    // a child that fails half-way leaves truncated output, which must not pass for a complete one
+    let exited = |status: Option<ExitStatus>| match status {
+        Some(status) if status.success() => Ok(true),
+        Some(status) => Err(std::io::Error::other(format!("exited with {}", status))),
+        None => Ok(false),
+    };
+    let deadline = match (deadline, children.deadline) {
+        (Some(bed), Some(total)) => Some(bed.min(total)),
+        (bed, total) => bed.or(total),
+    };
+    let Some(deadline) = deadline else {
+        let out = read_batched(stdout, batch_bytes, out)?;
+        return Ok(exited(children.wait(id)?)?.then_some(out));
+    };
+    let reader = std::thread::spawn(move || read_batched(stdout, batch_bytes, out));
+    let status = loop {
+        match children.state(id)? {
+            ChildState::Exited(status) => break status,
+            ChildState::Killed => return Ok(None),
+            ChildState::Running => {}
+        }
+        if Instant::now() >= deadline {
+            // at --timeout-total the intersects of the other BED files go as well
+            if children.timed_out() {
+                children.kill_all();
+            } else {
+                children.kill(id)?;
+            }
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    let out = reader.join()
+        .map_err(|_| std::io::Error::other("reader thread panicked"))??;
+    exited(Some(status))?;
+    Ok(Some(out))
+}
+
+// intersect output held in memory, or written to the --keep-temp directory and memory-mapped from there
+enum Intermediate {
+    Memory(Vec<u8>),
+    File(PathBuf),
+}
+
+impl Intermediate {
+    // runs the child to completion, None when it ran over the deadline
+    fn collect(cli: &Cli, children: &Children, child: Child, deadline: Option<Instant>, path: Option<PathBuf>) -> std::io::Result<Option<Intermediate>> {
+        let batch_bytes = cli.stream_batch_bytes as usize;
+        Ok(match path {
+            Some(path) => {
+                let file = std::io::BufWriter::new(fs::File::create(&path)?);
+                wait_with_deadline(children, child, deadline, batch_bytes, file)?.map(|_| Intermediate::File(path))
+            }
+            None => wait_with_deadline(children, child, deadline, batch_bytes, vec![])?.map(Intermediate::Memory),
+        })
+    }
+
+    fn is_blank(&self) -> std::io::Result<bool> {
+        match self {
+            Intermediate::Memory(bytes) => Ok(bytes.iter().all(u8::is_ascii_whitespace)),
+            // stops at the first record instead of loading the file
+            Intermediate::File(path) => {
+                for byte in std::io::BufReader::new(fs::File::open(path)?).bytes() {
+                    if !byte?.is_ascii_whitespace() {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    fn first_line(&self) -> std::io::Result<String> {
+        match self {
+            Intermediate::Memory(bytes) => Ok(String::from_utf8_lossy(bytes.split(|&b| b == b'\n').next().unwrap_or_default()).to_string()),
+            Intermediate::File(path) => {
+                let mut line = String::new();
+                std::io::BufRead::read_line(&mut std::io::BufReader::new(fs::File::open(path)?), &mut line)?;
+                Ok(line.trim_end_matches('\n').to_string())
+            }
+        }
+    }
+
+    // the value of `tag` on every SAM line, the optional fields vary in number and order so
+    // they are searched line by line instead of projected
+    fn sam_tag(&self, tag: &str) -> std::io::Result<Vec<Option<String>>> {
+        let prefix = format!("{}:", tag);
+        let value = |line: &str| line.split('\t').skip(11)
+            .find(|field| field.starts_with(&prefix))
+            .and_then(|field| field.splitn(3, ':').nth(2))
+            .map(str::to_string);
+        match self {
+            Intermediate::Memory(bytes) => Ok(String::from_utf8_lossy(bytes).lines()
+                .filter(|line| !line.is_empty())
+                .map(value)
+                .collect()),
+            Intermediate::File(path) => std::io::BufRead::lines(std::io::BufReader::new(fs::File::open(path)?))
+                .filter(|line| line.as_ref().map_or(true, |line| !line.is_empty()))
+                .map(|line| line.map(|line| value(&line)))
+                .collect(),
+        }
+    }
+
+    fn parse(&self, projection: Vec<usize>) -> PolarsResult<DataFrame> {
+        fn tab_separated<R: polars::io::mmap::MmapBytesReader>(reader: CsvReader<R>, projection: Vec<usize>) -> PolarsResult<DataFrame> {
+            reader
+                .with_delimiter(b'\t')
+                .has_header(false)
+                .with_projection(Some(projection))
+                .finish()
+        }
+        match self {
+            Intermediate::Memory(bytes) => tab_separated(CsvReader::new(Cursor::new(bytes)), projection),
+            Intermediate::File(path) => tab_separated(CsvReader::from_path(path)?, projection),
+        }
+    }
+}
+
+// Skipped when the intersect of this BED file ran over --per-bed-timeout, TimedOut when the run
+// reached --timeout-total
+fn screen_bed(cli: &Cli, children: &Children, align_file: &Path, region_path: &Path, file_name: &str) -> ReadlocResult<BedOutcome> {
+    #[cfg(feature = "native")]
+    if cli.engine == Engine::Native {
+        return screen_bed_native(cli, align_file, region_path, file_name);
+    }
+    info!("\x1b[44mStart screening overlap to {}\x1b[m", file_name);
+    let deadline = cli.per_bed_timeout.map(|secs| Instant::now() + Duration::from_secs(secs));
+    let failed = |cmd: &'static str| move |source: std::io::Error| ReadlocError::Command { cmd, bed: file_name.to_string(), source };
+    let over_time = || if children.timed_out() {
+        BedOutcome::TimedOut(file_name.to_string())
+    } else {
+        BedOutcome::Skipped(file_name.to_string())
+    };
+    let split = (!cli.no_split).then_some("-split");
+    let cache = cli.cache_dir.as_deref()
+        .filter(|_| !cli.no_cache && !is_run_temporary(align_file) && !is_run_temporary(region_path))
+        .and_then(|dir| CacheEntry::new(dir, align_file, region_path, cli.strand.bedtools_flag(), !cli.no_split, &cli.bedtools));
+    let (bamout, bedout) = match cache.as_ref().and_then(CacheEntry::load) {
+        Some((sam, bed)) => {
+            info!("Reusing the cached intersect output in \x1b[33m{}\x1b[m", sam.parent().unwrap().to_string_lossy());
+            (Intermediate::File(sam), Intermediate::File(bed))
+        }
+        None => {
+            let staging = cache.as_ref().map(CacheEntry::staging).transpose().map_err(failed("bedtools intersect -ubam"))?;
+            // named after the BED file like the --dump-intermediate frames
+            let keep_path = |ext: &str| match staging.as_ref() {
+                Some(staging) => Some(staging.path().join(format!("intersect.{}", ext))),
+                None => cli.keep_temp.as_deref().map(|dir| {
+                    dir.join(format!("{}.intersect.{}", Path::new(file_name).file_stem().unwrap().to_string_lossy(), ext))
+                }),
+            };
+            // the BED12 side first, a file without overlaps then costs a single pass over the alignment
+            let mut bed_cmd = Command::new(&cli.bedtools);
+            bed_cmd.arg("intersect").args(cli.strand.bedtools_flag())
+                .arg("-a").arg(align_file)
+                .arg("-b").arg(region_path)
+                .arg("-wo").args(split).arg("-bed");
+            debug!("Running {:?}", bed_cmd);
+            let bedraw = bed_cmd
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .map_err(failed("bedtools intersect -bed"))?;
+            let Some(bedout) = Intermediate::collect(cli, children, bedraw, deadline, keep_path("bed"))
+                .map_err(failed("bedtools intersect -bed"))? else {
+                return Ok(over_time());
+            };
+            // no read overlaps the file, there is nothing for the CSV reader to parse
+            if bedout.is_blank().map_err(failed("bedtools intersect -bed"))? {
+                // cached as well, with the SAM side left empty
+                if let (Some(cache), Some(staging)) = (cache.as_ref(), staging) {
+                    fs::write(staging.path().join("intersect.sam"), "").map_err(failed("samtools view"))?;
+                    cache.store(staging).map_err(failed("samtools view"))?;
+                }
+                return Ok(BedOutcome::Empty(file_name.to_string()));
+            }
+
+            let mut bam_cmd = Command::new(&cli.bedtools);
+            bam_cmd.arg("intersect").args(cli.strand.bedtools_flag())
+                .arg("-a").arg(align_file)
+                .arg("-b").arg(region_path)
+                .arg("-wa").args(split).arg("-ubam");
+            debug!("Running {:?} | {:?} view -", bam_cmd, cli.samtools);
+            let mut bamraw = bam_cmd
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .map_err(failed("bedtools intersect -ubam"))?;
+            let bamraw_out = bamraw.stdout.take()
+                .ok_or_else(|| failed("bedtools intersect -ubam")(std::io::Error::other("stdout is not piped")))?;
+            // registered as well, so the deadline kills both ends of the pipe
+            let Some(bamraw) = children.register(bamraw).map_err(failed("bedtools intersect -ubam"))? else {
+                return Ok(over_time());
+            };
+
+            let bamview = Command::new(&cli.samtools)
+                .args(["view", "-"])
+                .stdin(Stdio::from(bamraw_out))
+                .stdout(Stdio::piped())
+                .spawn()
+                .map_err(failed("samtools view"))?;
+    
+            let bamout = Intermediate::collect(cli, children, bamview, deadline, keep_path("sam"))
+                .map_err(failed("samtools view"))?;
+            if bamout.is_none() {
+                children.kill(bamraw).ok();
+            }
+            let bamraw_status = children.wait(bamraw).map_err(failed("bedtools intersect -ubam"))?;
+            let (Some(bamout), Some(bamraw_status)) = (bamout, bamraw_status) else {
+                return Ok(over_time());
+            };
+            if !bamraw_status.success() {
+                return Err(failed("bedtools intersect -ubam")(std::io::Error::other(format!("exited with {}", bamraw_status))));
+            }
+            // only complete output is stored, a file skipped over --per-bed-timeout is intersected again next time
+            match (cache.as_ref(), staging) {
+                (Some(cache), Some(staging)) => {
+                    let (sam, bed) = cache.store(staging).map_err(failed("samtools view"))?;
+                    (Intermediate::File(sam), Intermediate::File(bed))
+                }
+                _ => (bamout, bedout),
+            }
+        }
+    };
+    if bedout.is_blank().map_err(failed("bedtools intersect -bed"))? {
+        return Ok(BedOutcome::Empty(file_name.to_string()));
+    }
+    // both halves come from the same intersect, SAM records missing altogether point at samtools
+    // rather than at the data, and the join would silently drop every read of the file
+    if bamout.is_blank().map_err(failed("samtools view"))? {
+        return Err(failed("samtools view")(std::io::Error::other(
+            "no SAM records although bedtools reported overlaps, check that this samtools reads the BAM written by bedtools -ubam"
+        )));
+    }
+    // `-wo -bed` writes the alignment as BED12, then the region line and the overlap, so the
+    // projection below holds from BED4 on whatever the flavor; BED3 has no name to assign reads to
+    let n_fields = bedout.first_line().map_err(failed("bedtools intersect -bed"))?.split('\t').count();
+    if n_fields < 12 + 4 + 1 {
+        return Err(ReadlocError::Bed {
+            op: "screen",
+            bed: file_name.to_string(),
+            reason: format!("{} columns, at least 4 (chrom, start, end, name) are needed", n_fields.saturating_sub(13)),
+        });
+    }
+    
+    let mut bam_projection = vec![0, 2, 3, 5];
+    if cli.qual_weight {
+        bam_projection.push(10);
+    }
+    if cli.needs_flag() || cli.primary_only {
+        bam_projection.push(1);
+    }
+    if cli.min_mapq.is_some() || cli.weight == Weight::Mapq {
+        bam_projection.push(4);
+    }
+    let mut bamdf = bamout.parse(bam_projection)
+        .map_err(|source| ReadlocError::Parse { what: "BAM", bed: file_name.to_string(), source })?;
+    let mut beddf = bedout.parse(vec![0, 1, 2, 3, 5, 13, 14, 15])
+        .map_err(|source| ReadlocError::Parse { what: "BED", bed: file_name.to_string(), source })?;
+    // a tab inside a read name shifts every following SAM field
+    let position = bamdf.column("column_4")
+        .map_err(|_| ReadlocError::MissingColumn { column: "POS", what: "BAM", bed: file_name.to_string() })?;
+    if position.dtype() != &DataType::Int64 {
+        return Err(ReadlocError::Bed {
+            op: "parse the SAM records overlapping",
+            bed: file_name.to_string(),
+            reason: "misaligned fields, read names may contain tabs".to_string(),
+        });
+    }
+    check_read_names(&mut bamdf, "column_1", cli.sanitize_names, file_name)?;
+    check_read_names(&mut beddf, "column_4", cli.sanitize_names, file_name)?;
+    if let Some(tag) = cli.umi_tag.as_deref() {
+        let umis = bamout.sam_tag(tag).map_err(failed("samtools view"))?;
+        if umis.len() != bamdf.height() {
+            return Err(ReadlocError::Bed {
+                op: "match the SAM tags of the records overlapping",
+                bed: file_name.to_string(),
+                reason: format!("{} SAM lines but {} records, cannot match the {} tags to them", umis.len(), bamdf.height(), tag),
+            });
+        }
+        bamdf.with_column(Series::new("umi", umis))?;
+    }
+    // before the MAPQ filter drops the column
+    match cli.weight {
+        Weight::None => {}
+        Weight::Mapq => {
+            bamdf = bamdf.lazy()
+                .with_column(
+                    when(col("column_5").eq(lit(255)))
+                        .then(lit(0.0))
+                        .otherwise(col("column_5").cast(DataType::Float64))
+                        .alias("weight"))
+                .collect()?;
+        }
+        Weight::As => {
+            let scores = bamout.sam_tag("AS").map_err(failed("samtools view"))?;
+            if scores.len() != bamdf.height() {
+                return Err(ReadlocError::Bed {
+                    op: "match the SAM tags of the records overlapping",
+                    bed: file_name.to_string(),
+                    reason: format!("{} SAM lines but {} records, cannot match the AS tags to them", scores.len(), bamdf.height()),
+                });
+            }
+            let weights: Vec<f64> = scores.iter()
+                .map(|score| score.as_deref().and_then(|score| score.parse::<f64>().ok()).map_or(0.0, |score| score.max(0.0)))
+                .collect();
+            bamdf.with_column(Series::new("weight", weights))?;
+        }
+    }
+    // before the MAPQ and FLAG filters, which drop SAM records on purpose
+    let check = check_join(&beddf, &bamdf)?;
+    if check.unmatched as f64 > check.alignments as f64 * MAX_UNMATCHED_FRACTION {
+        let hint = match check.offset {
+            Some(offset) if offset != SAM_POS_OFFSET => format!(", their SAM POS is the BED start + {} instead of + {}; check the bedtools and samtools versions", offset, SAM_POS_OFFSET),
+            _ => String::new(),
+        };
+        warn!("{} of {} alignments overlapping {} have no SAM record at the same position and are dropped{}",
+            check.unmatched, check.alignments, file_name, hint);
+    }
+    // a read whose other alignments fall below the threshold is left with one candidate
+    // and goes through the unique branch like any other uniquely overlapping read
+    if let Some(min_mapq) = cli.min_mapq {
+        bamdf = bamdf.lazy()
+            .filter(col("column_5").gt_eq(lit(min_mapq as i64)))
+            .select([col("*").exclude(["column_5"])])
+            .collect()?;
+    }
+    // the other records of a single read would otherwise compete with its primary alignment as multi-mapping evidence
+    if cli.primary_only {
+        let primary = col("column_2")
+            .map(|s| Ok(Some(s.i64()?.apply(|flag| flag & 0x900).into_series())), GetOutput::same_type())
+            .eq(lit(0));
+        let kept = if cli.needs_flag() { col("*") } else { col("*").exclude(["column_2"]) };
+        bamdf = bamdf.lazy()
+            .filter(primary)
+            .select([kept])
+            .collect()?;
+    }
+    info!("\x1b[42mFinished overlapping\x1b[m");
+    let joined_df = with_source(cli, join_overlaps(beddf, bamdf, cli.join_strategy), file_name);
+    dump_joined(cli, &joined_df, file_name)?;
+    Ok(BedOutcome::Screened(Box::new(joined_df)))
 }
 
+// every candidate carries its BED file; under --namespace-regions the region name is prefixed
+// with the file stem too, so `gene.bed` and `exon.bed` regions sharing a name are counted,
+// tie-broken and partitioned apart
+fn with_source(cli: &Cli, joined_df: LazyFrame, file_name: &str) -> LazyFrame {
+    let joined_df = joined_df.with_column(lit(file_name).alias("source"));
+    if !cli.namespace_regions {
+        return joined_df;
+    }
+    joined_df.with_column((lit(region_namespace(file_name)) + col("region")).alias("region"))
+}
+
+// the prefix --namespace-regions gives the region names of `file_name`, its stem and a colon
+fn region_namespace(file_name: &str) -> String {
+    let stem = file_name.strip_suffix(".gz").unwrap_or(file_name);
+    let stem = stem.strip_suffix(".bed").unwrap_or(stem);
+    format!("{}:", stem)
+}
 
-fn merge_range(vec: &Vec<(i64, i64)>) -> Vec<(i64, i64)> {
-    let mut merged = vec![];
-    if vec.is_empty() {
-        return merged;
+fn dump_joined(cli: &Cli, joined_df: &LazyFrame, file_name: &str) -> PolarsResult<()> {
+    if let Some(dump_dir) = cli.dump_intermediate.as_deref() {
+        let mut dump_path = dump_dir.to_path_buf();
+        dump_path.push(format!("{}.joined.parquet", Path::new(file_name).file_stem().unwrap().to_string_lossy()));
+        let mut dump_file = fs::File::create(&dump_path)?;
+        ParquetWriter::new(&mut dump_file).finish(&mut joined_df.clone().collect()?)?;
+        info!("Intermediate frame in \x1b[33m{}\x1b[m", dump_path.to_string_lossy());
     }
-    
-    let mut start = vec[0].0;
-    let mut end = vec[0].1;
-    
-    for &(s, e) in vec.iter().skip(1) {
-        if s <= end {
-            end = end.max(e);
-        } else {
-            merged.push((start, end));
-            start = s;
-            end = e;
+    Ok(())
+}
+
+// same frame as the bedtools path, read straight from the BAM; --per-bed-timeout does not apply
+#[cfg(feature = "native")]
+fn screen_bed_native(cli: &Cli, align_file: &Path, region_path: &Path, file_name: &str) -> ReadlocResult<BedOutcome> {
+    info!("\x1b[44mStart screening overlap to {} (native)\x1b[m", file_name);
+    let regions = read_regions(region_path)?;
+    let mut df = native::intersect(align_file, &regions, cli.strand, !cli.no_split, cli.qual_weight, cli.needs_flag(), cli.umi_tag.as_deref(), cli.weight, cli.min_mapq, cli.primary_only)?;
+    if df.height() == 0 {
+        return Ok(BedOutcome::Empty(file_name.to_string()));
+    }
+    check_read_names(&mut df, "read", cli.sanitize_names, file_name)?;
+    info!("\x1b[42mFinished overlapping\x1b[m");
+    let joined_df = with_source(cli, df.lazy(), file_name);
+    dump_joined(cli, &joined_df, file_name)?;
+    Ok(BedOutcome::Screened(Box::new(joined_df)))
+}
+
+// screen_bed behind the --checkpoint-dir file `checkpoint` of the BED file, a joined frame is written
+// under a temporary name and renamed once complete, so an interrupted write is screened again
+fn screen_bed_checkpointed(cli: &Cli, children: &Children, align_file: &Path, region_path: &Path, file_name: &str, checkpoint: &Path) -> ReadlocResult<BedOutcome> {
+    // a file without overlaps leaves a marker, there is no frame to write
+    let empty_marker = checkpoint.with_extension("empty");
+    if checkpoint.is_file() {
+        info!("Resuming {} from the checkpoint \x1b[33m{}\x1b[m", file_name, checkpoint.to_string_lossy());
+        return Ok(BedOutcome::Screened(Box::new(LazyFrame::scan_parquet(checkpoint, ScanArgsParquet::default())?)));
+    }
+    if empty_marker.is_file() {
+        return Ok(BedOutcome::Empty(file_name.to_string()));
+    }
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| ReadlocError::Io { op: "write checkpoint", path, source }
+    };
+    match screen_bed(cli, children, align_file, region_path, file_name)? {
+        BedOutcome::Screened(joined_df) => {
+            let mut joined = joined_df.collect()?;
+            let partial = checkpoint.with_extension("partial");
+            let mut partial_file = fs::File::create(&partial).map_err(io_error(&partial))?;
+            ParquetWriter::new(&mut partial_file).finish(&mut joined)?;
+            fs::rename(&partial, checkpoint).map_err(io_error(checkpoint))?;
+            Ok(BedOutcome::Screened(Box::new(joined.lazy())))
+        }
+        BedOutcome::Empty(file_name) => {
+            fs::write(&empty_marker, "").map_err(io_error(&empty_marker))?;
+            Ok(BedOutcome::Empty(file_name))
         }
+        // over --per-bed-timeout or --timeout-total, tried again on the next run
+        outcome => Ok(outcome),
     }
-    
-    merged.push((start, end));
-    
-    merged
 }
-// the largest chromosome chr1 size 248,956,422 is smaller than i64 max 4294,967,295
-fn cigar_parser(cigar: &str, offset: i64) -> Vec<(i64, i64)> {
-    let mut valid_ranges = vec![];
-    let mut start: i64 = 0;
-    let mut end: i64 = 0;
-    let mut i: usize = 0;
-    while i < cigar.len() {
-        let mut j = i;
-        while j < cigar.len() && cigar[j..=j].chars().next().unwrap().is_digit(10) {
-            j += 1;
-        }
-        let num = cigar[i..j].parse::<i64>().unwrap();
-        
-        if cigar[j..=j] == "M".to_string() || cigar[j..=j] == "D".to_string() {
-            end += num;
-            valid_ranges.push((start + offset, end + offset));
-            start = end;
-        } else if cigar[j..=j] == "N".to_string() {
-            end += num;
-            start = end;
-        } 
-        i = j + 1;
+
+// every option screen_bed reads that changes the joined frames, one per line as name and value
+fn checkpoint_options(cli: &Cli) -> String {
+    let name = |value: Option<clap::builder::PossibleValue>| value.unwrap().get_name().to_string();
+    let options = [
+        ("engine", name(cli.engine.to_possible_value())),
+        ("strand", name(cli.strand.to_possible_value())),
+        ("no-split", cli.no_split.to_string()),
+        ("qual-weight", cli.qual_weight.to_string()),
+        ("flag", (cli.needs_flag() || cli.primary_only).to_string()),
+        ("primary-only", cli.primary_only.to_string()),
+        ("min-mapq", cli.min_mapq.map_or("none".to_string(), |mapq| mapq.to_string())),
+        ("weight", name(cli.weight.to_possible_value())),
+        ("umi-tag", cli.umi_tag.clone().unwrap_or_else(|| "none".to_string())),
+        ("sanitize-names", cli.sanitize_names.to_string()),
+        ("namespace-regions", cli.namespace_regions.to_string()),
+    ];
+    options.iter().map(|(option, value)| format!("{}\t{}\n", option, value)).collect()
+}
+
+// the options of the first run are stored as `options` next to the checkpoints, a run with other
+// values would mix frames of both and is refused instead of resumed
+fn check_checkpoint_options(cli: &Cli, dir: &Path) -> ReadlocResult<()> {
+    let path = dir.join("options");
+    let options = checkpoint_options(cli);
+    let Ok(stored) = fs::read_to_string(&path) else {
+        return fs::write(&path, options).map_err(|source| ReadlocError::Io { op: "write checkpoint", path, source });
+    };
+    if stored == options {
+        return Ok(());
+    }
+    let changed: Vec<String> = options.lines()
+        .filter(|line| !stored.lines().any(|stored| stored == *line))
+        .map(|line| format!("--{}", line.replace('\t', " ")))
+        .collect();
+    let options = if changed.is_empty() { "other options".to_string() } else { format!("other values than {}", changed.join(", ")) };
+    Err(ReadlocError::Invalid {
+        what: "checkpoint directory",
+        path: dir.to_path_buf(),
+        reason: format!("its checkpoints were written with {}, remove it or pass another --checkpoint-dir", options),
+    })
+}
+
+// the file becomes command line arguments for the options not given on the command line,
+// so clap validates them like any other; keys are long option names with - or _
+fn config_args(path: &Path, given: &ArgMatches) -> ReadlocResult<Vec<OsString>> {
+    let content = fs::read_to_string(path).map_err(|source| ReadlocError::Io { op: "read", path: path.to_path_buf(), source })?;
+    let invalid = |reason: String| ReadlocError::Invalid { what: "config file", path: path.to_path_buf(), reason };
+    let table: toml::Table = toml::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+    let command = Cli::command();
+    let mut args: Vec<OsString> = vec![];
+    let mut unknown: Vec<&str> = vec![];
+    for (key, value) in table.iter() {
+        let id = key.replace('-', "_");
+        let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == id.as_str() && id != "config" && id != "help") else {
+            unknown.push(key);
+            continue;
+        };
+        if given.value_source(&id) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let flag = match (arg.get_long(), arg.get_short()) {
+            (Some(long), _) => format!("--{}", long),
+            (None, Some(short)) => format!("-{}", short),
+            (None, None) => unreachable!("options have a long or short name"),
+        };
+        let values: Vec<&toml::Value> = match value {
+            // a switch is given by its flag alone, and a false one is left out
+            toml::Value::Boolean(true) => vec![],
+            toml::Value::Boolean(false) => continue,
+            toml::Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        args.push(flag.into());
+        for value in values {
+            args.push(match value {
+                toml::Value::String(s) => s.into(),
+                toml::Value::Integer(i) => i.to_string().into(),
+                toml::Value::Float(f) => f.to_string().into(),
+                _ => return Err(invalid(format!("key {} must be a string, number, boolean or array of strings and numbers", key))),
+            });
+        }
+    }
+    if !unknown.is_empty() {
+        return Err(invalid(format!("unknown keys {}", unknown.join(", "))));
+    }
+    Ok(args)
+}
+
+// only options the user left unset or at their default are changed, and only those are logged
+fn apply_platform_defaults(cli: &mut Cli, matches: &ArgMatches) {
+    let Some(platform) = cli.platform else {
+        return;
+    };
+    let unset = |id: &str| matches.value_source(id).is_none_or(|source| source == ValueSource::DefaultValue);
+    // short reads are placed by most of their length, long reads lose hundreds of bases to
+    // adapters, clipping and indels, so only a substantial overlap or alignment counts
+    let (min_coverage, min_read_length) = match platform {
+        Platform::Short => (20, 30),
+        Platform::Long => (100, 500),
+    };
+    let mut applied = vec![];
+    if unset("min_coverage") {
+        cli.min_coverage = Some(min_coverage);
+        applied.push(format!("--min-coverage {}", min_coverage));
+    }
+    if unset("min_read_length") {
+        cli.min_read_length = Some(min_read_length);
+        applied.push(format!("--min-read-length {}", min_read_length));
+    }
+    if platform == Platform::Long && unset("join_strategy") {
+        // the BAM side with its long CIGAR strings dominates, so both join inputs are built in parallel
+        cli.join_strategy = JoinStrategy::Parallel;
+        applied.push("--join-strategy parallel".to_string());
+    }
+    if !applied.is_empty() {
+        info!("Applied defaults for {} reads: {}", platform.to_possible_value().unwrap().get_name(), applied.join(", "));
     }
-    valid_ranges = merge_range(&valid_ranges);
-    valid_ranges
 }
 
-fn calc_coverage(a: i64, b: i64, c: i64, d: i64, cigar: &str) -> i64{
-    let ranges: Vec<(i64, i64)> = cigar_parser(cigar, a);
-    assert_eq!(b, ranges[ranges.len()-1].1);
-    let mapped_vec: Vec<i64> = ranges.iter().map(|range| {
-        let btm = range.0.max(c);
-        let top = range.1.min(d);
-        let cov: i64;
-        if top > btm {
-            cov = top - btm;
+// the colour codes of a message stay only on a terminal, a log file or pipe gets the plain text
+fn colored(terminal: bool, message: String) -> String {
+    if terminal {
+        return message;
+    }
+    let mut plain = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|&c| c == 'm');
         } else {
-            cov = 0;
+            plain.push(c);
         }
-        cov
-    }).collect();
-    let coverage = mapped_vec.iter().sum();
-    coverage
+    }
+    plain
 }
 
-fn check_command(cmd: &str) {
-    let cmd_check = Command::new(cmd)
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status().unwrap();
-    if !cmd_check.success() {
-        println!("{} is not installed.", cmd);
-        exit(1);
-    }
-}
-
-fn main() -> PolarsResult<()> {
-    let cli = Cli::parse();
-    check_command("bedtools");
-
-    let abs_align_file = cli.a.canonicalize()?;
-    let abs_region_dir = cli.r.canonicalize()?;
-
-    let merged: LazyFrame;
-    if let Ok(entries) = fs::read_dir(abs_region_dir.clone()) {
-        let mut dfs = vec![];
-        for entry in entries {
-            if let Ok(entry) = entry {
-                let mut abs_region_path: PathBuf = abs_region_dir.clone();
-                let file_name = entry.file_name().to_string_lossy().to_string();
-                let extension = Path::new(&file_name).extension();
-                if let Some(ext) = extension {
-                    if ext == "bed" {
-                        println!("\x1b[44mStart screening overlap to {}\x1b[m", file_name);
-                        abs_region_path.push(file_name.clone());
-                        let bamraw = Command::new("bedtools")
-                            .args(&["intersect", "-s", "-a", abs_align_file.to_str().unwrap(), 
-                                "-b", abs_region_path.to_str().unwrap(), "-wa", "-split", "-ubam"])
-                            .stdout(Stdio::piped())
-                            .stderr(Stdio::null())
-                            .spawn()
-                            .expect("Error: bedtools intersect failed");
-                        let bamraw_out = bamraw.stdout.expect("Error: failed to open bedtools intersect stdout");
-
-                        let bamview = Command::new("samtools")
-                            .args(&["view", "-"])
-                            .stdin(Stdio::from(bamraw_out))
-                            .stdout(Stdio::piped())
-                            .spawn()
-                            .expect("Error: failed to samtools view bam result");
-                    
-                        let bamout = bamview.wait_with_output().expect("Error: failed to open samtools view stdout");
-                        let bamreader = Cursor::new(&bamout.stdout);
-                    
-                        let bedraw = Command::new("bedtools")
-                            .args(&["intersect", "-s", "-a", abs_align_file.to_str().unwrap(), 
-                                        "-b", abs_region_path.to_str().unwrap(), "-wo", "-split", "-bed"])
-                            .output()
-                            .expect("Error: bedtools intersect for BED failed");
-                        let bedreader = Cursor::new(&bedraw.stdout);
-                    
-                        let bamdf = CsvReader::new(bamreader)
-                            .with_delimiter(b'\t')
-                            .has_header(false)
-                            .with_projection(Some(vec![0, 2, 3, 5]))
-                            .finish().expect("failed to create dataframe from bam");
-                        let beddf = CsvReader::new(bedreader)
-                            .with_delimiter(b'\t')
-                            .has_header(false)
-                            .with_projection(Some(vec![0, 1, 2, 3, 13, 14, 15]))
-                            .finish().expect("failed to create dataframe from bed");
-                        println!("\x1b[42mFinished overlapping\x1b[m");
-                        let joined_df = beddf.lazy()
-                            .join(bamdf.lazy(), 
-                                [col("column_4"), col("column_1")], 
-                                [col("column_1"), col("column_3")], 
-                                JoinType::Inner)
-                            .filter(
-                                (col("column_2") + lit(1)).eq(col("column_4_right")),
-                            )
-                            .rename(&[
-                                "column_1", "column_2", 
-                                "column_3", "column_4", 
-                                "column_14", "column_15", 
-                                "column_16", "column_6", ],
-                            &[
-                                "chr", "align_0", "align_1", "read", 
-                                "region_0", "region_1", "region", "cigar"
-                            ])
-                            .select(&[
-                                col("chr"), col("align_0"), col("align_1"), col("read"), 
-                                col("region_0"), col("region_1"), col("region"), col("cigar"), ]
-                            );
-                        dfs.push(joined_df);
-                    }
-                }
+// status lines go to stderr, info ones as they are and the other levels behind their name; installed
+// before the arguments are parsed so a broken config file is still reported, at info until -v or --quiet apply
+fn init_logging() {
+    let terminal = std::io::stderr().is_terminal();
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .parse_default_env()
+        .format(move |buf, record| {
+            let message = colored(terminal, record.args().to_string());
+            match record.level() {
+                Level::Info => writeln!(buf, "{}", message),
+                level => writeln!(buf, "{} {}", buf.default_styled_level(level), message),
             }
-        }
+        })
+        .init();
+    set_log_level(0, false);
+}
 
-        merged = concat(&dfs, false, true)?;
-        println!("\nshape: {:?} (unrefined)", merged.clone().collect()?.shape());
-    } else {
-        println!("Failed to read directory.");
-        exit(1);
+// -v adds the commands run and -vv everything else, --quiet keeps errors and warnings; RUST_LOG wins over both
+fn set_log_level(verbose: u8, quiet: bool) {
+    if std::env::var_os("RUST_LOG").is_some() {
+        return;
+    }
+    log::set_max_level(match (quiet, verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    });
+}
+
+fn main() {
+    init_logging();
+    if let Err(e) = run() {
+        error!("{}", e);
+        exit(if matches!(e, ReadlocError::TimedOut { .. }) { EXIT_TIMEOUT } else { 1 });
+    }
+}
+
+fn run() -> ReadlocResult<()> {
+    let mut argv: Vec<OsString> = std::env::args_os().collect();
+    if argv.iter().any(|arg| arg == "--version" || arg == "-V") {
+        // before the full parse, which would ask for -a and -r; --bedtools, --samtools and their variables still apply
+        let given = Cli::command().ignore_errors(true).get_matches_from(&argv);
+        if given.get_flag("version") {
+            print_versions(&given);
+            return Ok(());
+        }
+    }
+    if argv.iter().any(|arg| arg.to_string_lossy().starts_with("--config")) {
+        // options required on the command line may come from the file, so the first pass only finds it
+        let given = Cli::command().ignore_errors(true).get_matches_from(&argv);
+        if let Some(config) = given.get_one::<PathBuf>("config") {
+            argv.extend(config_args(config, &given)?);
+        }
+    }
+    let matches = Cli::command().get_matches_from(argv);
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    set_log_level(cli.verbose, cli.quiet);
+    match &cli.command {
+        Some(Commands::Merge(args)) => return merge_counts(args),
+        Some(Commands::Summarize(args)) => return summarize(args),
+        Some(Commands::MergeBed(args)) => return merge_bed(args),
+        None => {}
+    }
+    apply_platform_defaults(&mut cli, &matches);
+    if let Some(threads) = cli.threads {
+        rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()
+            .map_err(|e| ReadlocError::Usage(format!("Failed to set up {} threads: {}", threads, e)))?;
+    }
+    let start_time = Instant::now();
+    if cli.engine == Engine::Bedtools {
+        check_command("bedtools", &cli.bedtools)?;
+        check_command("samtools", &cli.samtools)?;
+    } else if cfg!(not(feature = "native")) {
+        return Err(ReadlocError::Usage("--engine native needs a build with the `native` feature.".to_string()));
     }
 
-    let mut abs_output_file: PathBuf;
+    if cli.tabix && cli.format != OutputFormat::Bed {
+        return Err(ReadlocError::Usage(format!("--tabix indexes the BED output and cannot be used with --format {}.", cli.format.to_possible_value().unwrap().get_name())));
+    }
+    if cli.compress && cli.format == OutputFormat::Parquet {
+        return Err(ReadlocError::Usage("--compress applies to the text formats, parquet is compressed already.".to_string()));
+    }
+    if let Some(dump_dir) = cli.dump_intermediate.as_deref() {
+        if ! dump_dir.is_dir() {
+            return Err(ReadlocError::Usage("Intermediate dump directory do not exist.".to_string()));
+        }
+    }
+    if let Some(keep_dir) = cli.keep_temp.as_deref() {
+        if !keep_dir.is_dir() {
+            return Err(ReadlocError::Usage("Directory for intersect output do not exist.".to_string()));
+        }
+    }
+    if let Some(checkpoint_dir) = cli.checkpoint_dir.as_deref() {
+        fs::create_dir_all(checkpoint_dir)
+            .map_err(|source| ReadlocError::Io { op: "create checkpoint directory", path: checkpoint_dir.to_path_buf(), source })?;
+    }
+    if let Some(cache_dir) = cli.cache_dir.as_deref().filter(|_| !cli.no_cache) {
+        fs::create_dir_all(cache_dir)
+            .map_err(|source| ReadlocError::Io { op: "create cache directory", path: cache_dir.to_path_buf(), source })?;
+    }
+    let abs_output_dir: PathBuf;
     if let Some(output) = cli.o.as_deref() {
         if ! output.is_dir() {
-            println!("Output directory do not exist.");
-            exit(1);
+            return Err(ReadlocError::Usage("Output directory do not exist.".to_string()));
         } 
-        abs_output_file = std::fs::canonicalize(output)?;
+        abs_output_dir = std::fs::canonicalize(output)?;
     } else {
-        abs_output_file = std::fs::canonicalize(".")?;
-    }
-    let task_name = abs_align_file
-        .file_stem()
-        .unwrap()
-        .to_str()
-        .unwrap();
-    abs_output_file.push(format!("{}.reloc.bed", task_name));
-
-    let uniq: LazyFrame = merged.clone().unique(Some(vec![String::from("read")]), UniqueKeepStrategy::None);
-    let duplicated: LazyFrame = merged.filter(
-        col("read").is_in(lit(uniq.clone().collect()?["read"].clone())).not()
-    );
-    let dupcov: LazyFrame = duplicated.clone()
-        .groupby_stable([col("read")])
-        .agg([
-            as_struct(&[col("align_0"), col("align_1"), col("region_0"), col("region_1"), col("cigar")])
-            .apply(|s| {
-                    let ca = s.struct_()?;
-                    let s_a = &ca.fields()[0];
-                    let s_b = &ca.fields()[1];
-                    let s_c = &ca.fields()[2];
-                    let s_d = &ca.fields()[3];
-                    let s_cigar = &ca.fields()[4];
-
-                    let ca_a = s_a.i64()?;
-                    let ca_b = s_b.i64()?;
-                    let ca_c = s_c.i64()?;
-                    let ca_d = s_d.i64()?;
-                    let ca_cigar = s_cigar.utf8()?;
-
-                    // iterate both `ChunkedArrays`
-                    let out: Int64Chunked = ca_a
-                        .into_iter()
-                        .zip(ca_b)
-                        .zip(ca_c)
-                        .zip(ca_d)
-                        .zip(ca_cigar)
-                        .map(|
-                            ((((opt_a, 
-                            opt_b),
-                            opt_c), 
-                            opt_d),
-                            opt_cigar
-                        )| match ((((
-                            opt_a, 
-                            opt_b),
-                            opt_c), 
-                            opt_d),
-                            opt_cigar
-                        ) {
-                            ((((Some(a), Some(b)), Some(c)), Some(d)), Some(cigar)) => Some(calc_coverage(a, b ,c, d, cigar)),
-                            _ => None
-                        })
-                        .collect();
-                    Ok(Some(out.into_series()))
-            },
-            GetOutput::from_type(DataType::Int64),
-        ).arg_max().alias("cov_idx"),
-        ]);
+        abs_output_dir = std::fs::canonicalize(".")?;
+    }
+    // the outputs are only written at the end, after the intersects
+    if tempfile::tempfile_in(&abs_output_dir).is_err() {
+        return Err(ReadlocError::Usage(format!("Output directory {} is not writable.", abs_output_dir.to_string_lossy())));
+    }
 
-    let dedup: LazyFrame = duplicated.join(dupcov, [col("read")], [col("read")], 
-        JoinType::Inner).groupby_stable([col("read")])
-        .agg([
-            col("*").exclude(&["cov_idx"]).take(col("cov_idx")).first()
-        ]).select([
-            col("chr"),
-            col("align_0"),
-            col("align_1"),
-            col("read"),
-            col("region_0"),
-            col("region_1"),
-            col("region"),
-            col("cigar")
-        ]);
+    let tmp_dir = cli.tmp_dir.clone().unwrap_or_else(std::env::temp_dir);
+    if !tmp_dir.is_dir() {
+        return Err(ReadlocError::Usage("Temporary directory do not exist.".to_string()));
+    }
+    // BED files buffered from stdin or converted from an annotation
+    let mut region_tmp: Option<TempDir> = None;
+    let mut stdin_alignment: Option<TempDir> = None;
+    let samples: Vec<Sample> = if let Some(manifest) = cli.sample_manifest.as_deref() {
+        read_manifest(manifest)?
+    } else {
+        if cli.a.len() > 1 && cli.empty_regions.is_some() {
+            return Err(ReadlocError::Usage("--empty-regions writes a single file and cannot be used with several alignments.".to_string()));
+        }
+        if cli.a.len() > 1 && cli.counts.is_some() {
+            return Err(ReadlocError::Usage("--counts writes a single file and cannot be used with several alignments.".to_string()));
+        }
+        if cli.a.len() > 1 && cli.report_candidates.is_some() {
+            return Err(ReadlocError::Usage("--report-candidates writes a single file and cannot be used with several alignments.".to_string()));
+        }
+        if cli.a.len() > 1 && cli.unassigned.is_some() {
+            return Err(ReadlocError::Usage("--unassigned writes a single file and cannot be used with several alignments.".to_string()));
+        }
+        if cli.a.len() > 1 && cli.summary.is_some() {
+            return Err(ReadlocError::Usage("--summary writes a single file and cannot be used with several alignments.".to_string()));
+        }
+        let region_dir = cli.r.as_ref().unwrap();
+        let from_stdin = cli.a.iter().filter(|a| a.as_os_str() == "-").count();
+        if from_stdin > 1 {
+            return Err(ReadlocError::Usage("Only one alignment can be read from stdin.".to_string()));
+        }
+        if from_stdin == 1 && region_dir.as_os_str() == "-" {
+            return Err(ReadlocError::Usage("The alignment and the regions cannot both be read from stdin.".to_string()));
+        }
+        if from_stdin == 1 && cli.name.is_none() {
+            return Err(ReadlocError::Usage("An alignment read from stdin has no file stem to name the output, pass one with --name.".to_string()));
+        }
+        let abs_region_dir = if region_dir.as_os_str() == "-" {
+            let buffered = buffer_stdin(&tmp_dir, "stdin.bed")?;
+            let abs_region_dir = buffered.path().to_path_buf();
+            region_tmp = Some(buffered);
+            abs_region_dir
+        } else if annotation::is_annotation(region_dir) {
+            let converted = annotation_regions(region_dir, cli.feature_type, &tmp_dir)?;
+            let abs_region_dir = converted.path().to_path_buf();
+            region_tmp = Some(converted);
+            abs_region_dir
+        } else {
+            region_dir.canonicalize()?
+        };
+        // the region directory is resolved once and shared, every alignment stays its own sample
+        // so reads of different files never meet, even when their names collide
+        let mut samples: Vec<Sample> = vec![];
+        for align_file in cli.a.iter() {
+            let (abs_align_file, task_name) = if align_file.as_os_str() == "-" {
+                let buffered = buffer_stdin(&tmp_dir, "stdin.bam")?;
+                let abs_align_file = buffered.path().join("stdin.bam");
+                stdin_alignment = Some(buffered);
+                (abs_align_file, cli.name.clone().unwrap())
+            } else {
+                let abs_align_file = align_file.canonicalize()
+                    .map_err(|_| ReadlocError::Usage(format!("Alignment {} do not exist.", align_file.to_string_lossy())))?;
+                let task_name = abs_align_file
+                    .file_stem()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string();
+                (abs_align_file, task_name)
+            };
+            if samples.iter().any(|s| s.name == task_name) {
+                return Err(ReadlocError::Usage(format!("Several alignments are named {}, their outputs would overwrite each other.", task_name)));
+            }
+            samples.push(Sample {
+                align_file: abs_align_file,
+                region_dir: abs_region_dir.clone(),
+                name: task_name,
+            });
+        }
+        samples
+    };
+
+    if let Some(reference) = cli.reference.as_deref() {
+        if !reference.is_file() {
+            return Err(ReadlocError::Usage("Reference FASTA do not exist.".to_string()));
+        }
+    }
+    let crams: Vec<bool> = samples.iter().map(|sample| is_cram(&sample.align_file)).collect();
+    if crams.iter().any(|&cram| cram) && cli.reference.is_none() {
+        return Err(ReadlocError::Usage("CRAM input needs the reference FASTA it was compressed against, pass it with --reference.".to_string()));
+    }
+    check_region_strands(cli.strand, &samples)?;
+    if cli.dry_run {
+        // the buffered stdin and converted annotation are removed when their guards drop
+        print_plan(&cli, &samples, &crams, &abs_output_dir)?;
+        return Ok(());
+    }
+
+    // the region directory of the sample that reached --timeout-total, the later ones are not started
+    let mut timed_out: Option<PathBuf> = None;
+    let mut reports: Vec<(String, (usize, usize), PathBuf)> = vec![];
+    for (sample, cram) in samples.iter().zip(crams) {
+        if samples.len() > 1 {
+            info!("\x1b[45mProcessing sample {}\x1b[m", sample.name);
+        }
+        if cram {
+            let decoded = decode_cram(&cli.samtools, &sample.align_file, cli.reference.as_deref().unwrap(), &tmp_dir)?;
+            let decoded_sample = Sample {
+                align_file: decoded.path().join("decoded.bam"),
+                region_dir: sample.region_dir.clone(),
+                name: sample.name.clone(),
+            };
+            let (sample_timed_out, shape, output) = run_sample(&cli, &decoded_sample, &abs_output_dir, start_time)?;
+            decoded.close()?;
+            timed_out = sample_timed_out.then(|| sample.region_dir.clone());
+            reports.push((sample.name.clone(), shape, output));
+        } else {
+            let (sample_timed_out, shape, output) = run_sample(&cli, sample, &abs_output_dir, start_time)?;
+            timed_out = sample_timed_out.then(|| sample.region_dir.clone());
+            reports.push((sample.name.clone(), shape, output));
+        }
+        if timed_out.is_some() {
+            break;
+        }
+    }
+    if samples.len() > 1 {
+        info!("\x1b[46mProcessed {} of {} samples\x1b[m", reports.len(), samples.len());
+        for (name, shape, output) in reports.iter() {
+            info!("{}\tshape: {:?}\t\x1b[33m{}\x1b[m", name, shape, output.to_string_lossy());
+        }
+    }
+    if let Some(buffered) = region_tmp {
+        buffered.close()?;
+    }
+    if let Some(buffered) = stdin_alignment {
+        buffered.close()?;
+    }
+    // an error rather than an exit, so the temporary directories still in scope are removed
+    if let Some(region_dir) = timed_out {
+        return Err(ReadlocError::TimedOut { region_dir, limit: cli.timeout_total.unwrap(), partial: true });
+    }
+    Ok(())
+}
+
+// the checks of a real run up to the first intersect, then the BED files and outputs of every sample
+fn print_plan(cli: &Cli, samples: &[Sample], crams: &[bool], output_dir: &Path) -> ReadlocResult<()> {
+    let extension = cli.format.to_possible_value().unwrap().get_name().to_string();
+    report!("\x1b[46mDry run, {} samples\x1b[m", samples.len());
+    for (sample, &cram) in samples.iter().zip(crams) {
+        let mut bed_files: Vec<String> = fs::read_dir(&sample.region_dir)
+            .map_err(|source| ReadlocError::Io { op: "read directory", path: sample.region_dir.clone(), source })?
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|file_name| is_region_file(Path::new(file_name)))
+            .collect();
+        if bed_files.is_empty() {
+            return Err(no_region_files(&sample.region_dir));
+        }
+        bed_files.sort();
+        report!("\x1b[45m{}\x1b[m", sample.name);
+        report!("Alignment: \x1b[33m{}\x1b[m{}", sample.align_file.to_string_lossy(), if cram { " (CRAM, decoded first)" } else { "" });
+        report!("Regions: {} BED files in \x1b[33m{}\x1b[m: {}", bed_files.len(), sample.region_dir.to_string_lossy(), bed_files.join(", "));
+        report!("Output: \x1b[33m{}\x1b[m", output_dir.join(format!("{}.reloc.{}", sample.name, extension)).to_string_lossy());
+    }
+    Ok(())
+}
+
+// whether the time limit was hit, the shape of the result and where it was written
+fn run_sample(cli: &Cli, sample: &Sample, output_dir: &Path, start_time: Instant) -> ReadlocResult<(bool, (usize, usize), PathBuf)> {
+    let (mut result, timed_out, em) = assign_reads(cli, sample, start_time)?;
+    if cli.self_check_determinism {
+        check_determinism(cli, sample, start_time, &mut result)?;
+    }
+
+    let mut abs_output_file = output_dir.to_path_buf();
+    let task_name = &sample.name;
+    let mut extension = cli.format.to_possible_value().unwrap().get_name().to_string();
+    // tabix needs BGZF, which is written directly instead of running bgzip afterwards
+    let compress = cli.compress || cli.tabix;
+    if compress {
+        extension.push_str(".gz");
+    }
+    if timed_out {
+        abs_output_file.push(format!("{}.partial.reloc.{}", task_name, extension));
+    } else {
+        abs_output_file.push(format!("{}.reloc.{}", task_name, extension));
+    }
+    info!("{:?}", result);
+    // counts and reports below keep working on the 0-based frame
+    let mut written = shift_starts(&result, cli.coord_base)?;
+
+    let outfile = std::fs::File::create(&abs_output_file)
+        .map_err(|source| ReadlocError::Io { op: "create", path: abs_output_file.clone(), source })?;
+    let mut outfile = std::io::BufWriter::new(outfile);
+    if compress {
+        let mut compressed = BgzfWriter::new(outfile);
+        write_result(cli.format, &mut compressed, &mut written)?;
+        outfile = compressed.finish()?;
+    } else {
+        write_result(cli.format, &mut outfile, &mut written)?;
+    }
+    outfile.flush()?;
+    if cli.tabix {
+        tabix_index(&abs_output_file, cli.coord_base)?;
+    }
+    let shape = result.shape();
+    info!("Results in \x1b[33m{}\x1b[m", abs_output_file.to_string_lossy());
+
+    if cli.partition_by_region {
+        let partition_dir = output_dir.join(format!("{}.regions", task_name));
+        let n_partitions = write_partitions(&written, &partition_dir, cli.max_partitions)?;
+        info!("{} region partitions in \x1b[33m{}\x1b[m", n_partitions, partition_dir.to_string_lossy());
+    }
+
+    if cli.cpm {
+        let mut counts = region_counts(&result, true)?;
+        let mut counts_file = output_dir.to_path_buf();
+        counts_file.push(format!("{}.counts.tsv", task_name));
+        let mut outfile = std::fs::File::create(counts_file.clone())?;
+        CsvWriter::new(&mut outfile).has_header(true).with_delimiter(b'\t').finish(&mut counts)?;
+        info!("Region counts in \x1b[33m{}\x1b[m", counts_file.to_string_lossy());
+    }
+
+    if let Some(counts_file) = cli.counts.as_deref() {
+        let mut counts = region_counts(&result, false)?;
+        if cli.with_coverage {
+            counts = with_region_length(counts, &result)?;
+        }
+        let mut outfile = std::fs::File::create(counts_file)
+            .map_err(|source| ReadlocError::Io { op: "create", path: counts_file.to_path_buf(), source })?;
+        CsvWriter::new(&mut outfile).has_header(true).with_delimiter(b'\t').finish(&mut counts)?;
+        info!("Region counts in \x1b[33m{}\x1b[m", counts_file.to_string_lossy());
+    }
+
+    if let Some(mut em) = em {
+        let mut em_file = output_dir.to_path_buf();
+        em_file.push(format!("{}.em.tsv", task_name));
+        let mut outfile = std::fs::File::create(em_file.clone())?;
+        CsvWriter::new(&mut outfile).has_header(true).with_delimiter(b'\t').finish(&mut em)?;
+        info!("EM region counts in \x1b[33m{}\x1b[m", em_file.to_string_lossy());
+    }
+
+    if let Some(truth) = cli.truth.as_deref() {
+        truth_report(&result, truth)?;
+    }
+
+    if let Some(empty_file) = cli.empty_regions.as_deref() {
+        let kept: HashSet<String> = cli.regions.as_deref().map(read_name_list).transpose()?.unwrap_or_default().into_iter().collect();
+        let n_empty = write_empty_regions(&result, &sample.region_dir, empty_file, &kept, cli.namespace_regions)?;
+        info!("{} regions without reads in \x1b[33m{}\x1b[m", n_empty, empty_file.to_string_lossy());
+    }
+    
+    Ok((timed_out, shape, abs_output_file))
+}
+
+// unique reads fix their region, every ambiguous read is split over its candidates in
+// proportion to abundance * coverage and the abundances are re-estimated from the split
+// until no abundance moves by more than `tol`; the result only depends on the start,
+// which is uniform unless a seed draws it at random
+fn em_counts(uniq: &LazyFrame, duplicated: &LazyFrame, metric: CoverageMetric, iters: usize, tol: f64, seed: Option<u64>) -> PolarsResult<DataFrame> {
+    let uniq = uniq.clone().select([col("region")]).collect()?;
+    let candidates = duplicated.clone()
+        .select([col("read"), col("region"), col("align_0"), col("align_1"), col("region_0"), col("region_1"), col("cigar")])
+        .collect()?;
+
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut regions: Vec<String> = vec![];
+    let mut region_idx = |region: &str| -> usize {
+        *index.entry(region.to_string()).or_insert_with(|| {
+            regions.push(region.to_string());
+            regions.len() - 1
+        })
+    };
+    let unique_regions: Vec<usize> = uniq["region"].utf8()?.into_iter().flatten().map(&mut region_idx).collect();
+
+    // per read, its candidate regions and their coverage
+    let mut reads: Vec<Vec<(usize, f64)>> = vec![];
+    let mut read_idx: HashMap<String, usize> = HashMap::new();
+    let rows = candidates["read"].utf8()?.into_iter()
+        .zip(candidates["region"].utf8()?)
+        .zip(candidates["align_0"].i64()?)
+        .zip(candidates["align_1"].i64()?)
+        .zip(candidates["region_0"].i64()?)
+        .zip(candidates["region_1"].i64()?)
+        .zip(candidates["cigar"].utf8()?);
+    for ((((((read, region), a), b), c), d), cigar) in rows {
+        let (Some(read), Some(region), Some(a), Some(b), Some(c), Some(d), Some(cigar)) = (read, region, a, b, c, d, cigar) else {
+            continue;
+        };
+        let cov = calc_coverage(a, b, c, d, cigar, metric).map_err(|e| PolarsError::ComputeError(e.into()))?;
+        let r = region_idx(region);
+        let i = *read_idx.entry(read.to_string()).or_insert_with(|| {
+            reads.push(vec![]);
+            reads.len() - 1
+        });
+        reads[i].push((r, cov as f64));
+    }
+
+    let n_regions = regions.len();
+    let mut unique_counts = vec![0.0; n_regions];
+    for r in unique_regions {
+        unique_counts[r] += 1.0;
+    }
+    let mut abundance: Vec<f64> = match seed {
+        Some(seed) => {
+            // xorshift64, a zero state would stay zero
+            let mut state = seed.max(1);
+            (0..n_regions).map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                0.5 + (state >> 11) as f64 / (1u64 << 53) as f64
+            }).collect()
+        }
+        None => vec![1.0; n_regions],
+    };
+    let total: f64 = abundance.iter().sum();
+    abundance.iter_mut().for_each(|x| *x /= total.max(f64::MIN_POSITIVE));
+
+    let n_reads = unique_counts.iter().sum::<f64>() + reads.len() as f64;
+    let mut counts = unique_counts.clone();
+    let mut converged = false;
+    let mut iteration = 0;
+    while iteration < iters {
+        iteration += 1;
+        counts.copy_from_slice(&unique_counts);
+        for candidates in reads.iter() {
+            let weights: Vec<f64> = candidates.iter().map(|&(r, cov)| abundance[r] * cov).collect();
+            let sum: f64 = weights.iter().sum();
+            for (&(r, _), w) in candidates.iter().zip(weights.iter()) {
+                // a read without covered candidates is split evenly
+                counts[r] += if sum > 0.0 { w / sum } else { 1.0 / candidates.len() as f64 };
+            }
+        }
+        let mut max_change: f64 = 0.0;
+        for (theta, count) in abundance.iter_mut().zip(counts.iter()) {
+            let updated = count / n_reads;
+            max_change = max_change.max((updated - *theta).abs());
+            *theta = updated;
+        }
+        if max_change <= tol {
+            converged = true;
+            break;
+        }
+    }
+    if converged {
+        info!("EM converged after {} iterations", iteration);
+    } else {
+        warn!("EM stopped after {} iterations without converging", iteration);
+    }
 
-    let mut chr_map: HashMap<String, u32> = HashMap::new();
-    for i in 1..=22 {
-        let chr = format!("chr{}", i);
-        chr_map.entry(chr).or_insert(i);
+    DataFrame::new(vec![
+        Series::new("region", regions),
+        Series::new("count", counts),
+    ])?.sort(["region"], false)
+}
+
+// precision is over the assigned reads that have a truth, recall over the truth reads,
+// accuracy over the reads in either set
+fn truth_report(result: &DataFrame, truth_file: &Path) -> ReadlocResult<()> {
+    if !truth_file.is_file() {
+        return Err(not_found("read", truth_file));
+    }
+    let schema = Schema::from_iter([
+        Field::new("column_4", DataType::Utf8),
+        Field::new("column_7", DataType::Utf8),
+    ]);
+    let truth = CsvReader::from_path(truth_file)?
+        .with_delimiter(b'\t')
+        .has_header(false)
+        .with_projection(Some(vec![3, 6]))
+        .with_dtypes(Some(Arc::new(schema)))
+        .finish()?
+        .lazy()
+        .select([col("column_4").alias("read"), col("column_7").alias("truth")])
+        .unique_stable(Some(vec!["read".to_string()]), UniqueKeepStrategy::First);
+    let assigned = result.clone().lazy()
+        .filter(col("region").is_not_null())
+        .select([col("read"), col("region")])
+        .unique_stable(Some(vec!["read".to_string()]), UniqueKeepStrategy::First);
+    let compared = truth
+        .join(assigned, [col("read")], [col("read")], JoinType::Outer)
+        .with_column(
+            when(col("truth").is_null()).then(lit("no_truth"))
+                .otherwise(when(col("region").is_null()).then(lit("unassigned"))
+                    .otherwise(when(col("truth").eq(col("region"))).then(lit("correct"))
+                        .otherwise(lit("wrong_region"))))
+                .alias("outcome")
+        )
+        .collect()?;
+    let count = |outcome: &str| -> PolarsResult<usize> {
+        Ok(compared["outcome"].utf8()?.into_iter().filter(|o| *o == Some(outcome)).count())
+    };
+    let (correct, wrong, unassigned, no_truth) = (count("correct")?, count("wrong_region")?, count("unassigned")?, count("no_truth")?);
+    let ratio = |n: usize, d: usize| if d == 0 { 0.0 } else { n as f64 / d as f64 };
+    report!("\x1b[46mTruth comparison against {}\x1b[m", truth_file.to_string_lossy());
+    report!("correct: {}, wrong region: {}, unassigned: {}, assigned without truth: {}", correct, wrong, unassigned, no_truth);
+    report!("precision: {:.4}, recall: {:.4}, accuracy: {:.4}",
+        ratio(correct, correct + wrong),
+        ratio(correct, correct + wrong + unassigned),
+        ratio(correct, compared.height()));
+    if wrong > 0 {
+        let confusion = compared.lazy()
+            .filter(col("outcome").eq(lit("wrong_region")))
+            .groupby([col("truth"), col("region")])
+            .agg([col("read").count().alias("reads")])
+            .sort_by_exprs([col("reads"), col("truth"), col("region")], [true, false, false], false)
+            .limit(10)
+            .collect()?;
+        report!("Most frequent confusions (truth -> assigned):\n{:?}", confusion);
+    }
+    Ok(())
+}
+
+// a label is empty when no read was assigned to it, all its BED lines are written in file order;
+// with --regions only the listed labels are considered. Under --namespace-regions the labels
+// carry the prefix of their file, as in the result
+fn write_empty_regions(result: &DataFrame, region_dir: &Path, empty_file: &Path, kept: &HashSet<String>, namespace: bool) -> ReadlocResult<usize> {
+    let assigned: HashSet<&str> = result["region"].utf8()?.into_iter().flatten().collect();
+    let mut bed_files: Vec<PathBuf> = fs::read_dir(region_dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| is_region_file(path))
+        .collect();
+    bed_files.sort();
+    let mut empty = String::new();
+    let mut labels: HashSet<String> = HashSet::new();
+    for bed_file in bed_files.iter() {
+        let prefix = if namespace { region_namespace(&bed_file.file_name().unwrap().to_string_lossy()) } else { String::new() };
+        for region in read_regions(bed_file)? {
+            let label = format!("{}{}", prefix, region.name);
+            if assigned.contains(label.as_str()) || !(kept.is_empty() || kept.contains(&label)) {
+                continue;
+            }
+            empty.push_str(&format!("{}\t{}\t{}\t{}\t0\t{}\n", region.chr, region.start, region.end, label, region.strand));
+            labels.insert(label);
+        }
+    }
+    fs::write(empty_file, empty)?;
+    Ok(labels.len())
+}
+
+// labels become file names, anything outside [A-Za-z0-9._-] is replaced and clashes get a numeric suffix
+fn write_partitions(result: &DataFrame, partition_dir: &Path, max_partitions: usize) -> ReadlocResult<usize> {
+    let result = result.filter(&result["region"].is_not_null())?;
+    let n_regions = result["region"].n_unique()?;
+    if n_regions > max_partitions {
+        return Err(ReadlocError::Usage(format!("{} regions exceed the limit of {} partitions, raise --max-partitions to write them.", n_regions, max_partitions)));
+    }
+    fs::create_dir_all(partition_dir)?;
+    let mut used: HashMap<String, usize> = HashMap::new();
+    let partitions = result.partition_by_stable(["region"])?;
+    for mut partition in partitions {
+        let label = partition["region"].utf8()?.get(0).unwrap_or("").to_string();
+        let mut file_stem: String = label.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' { c } else { '_' })
+            .collect();
+        if file_stem.is_empty() || file_stem.starts_with('.') {
+            file_stem.insert(0, '_');
+        }
+        let seen = used.entry(file_stem.clone()).or_insert(0);
+        *seen += 1;
+        if *seen > 1 {
+            file_stem = format!("{}_{}", file_stem, seen);
+        }
+        let mut outfile = fs::File::create(partition_dir.join(format!("{}.reloc.bed", file_stem)))?;
+        CsvWriter::new(&mut outfile).has_header(false).with_delimiter(b'\t').finish(&mut partition)?;
     }
-    chr_map.insert("chrX".to_string(), 97);
-    chr_map.insert("chrY".to_string(), 98);
-    chr_map.insert("chrM".to_string(), 99);
+    Ok(n_regions)
+}
 
-    let mut result = concat(&[
-        uniq,
-        dedup
-    ], false, false)?
+fn sort_by_position(lf: LazyFrame, chr_map: HashMap<String, u32>) -> LazyFrame {
+    // the ranked candidates of a read (--top-k) are listed best first
+    let ranked = lf.schema().is_ok_and(|schema| schema.contains("rank"));
+    // contigs missing from the map sort after the known ones, by name
+    let mut keys = vec![col("chr_n"), col("chr"), col("align_0"), col("align_1")];
+    if ranked {
+        keys.push(col("rank"));
+    }
+    // the multi-column sort of polars is not stable, so the region and read names break the
+    // remaining ties and the output does not depend on the intersect or concat order
+    keys.extend([col("region_0"), col("region_1"), col("region"), col("read")]);
+    let descending = vec![false; keys.len()];
+    lf
         .with_columns([
             col("chr").map(move |x: Series|{
                 let y:Series = x.utf8()?.into_iter().map(|c| {
-                    let v = chr_map.get(c.unwrap()).unwrap();
-                    v
+                    chr_map.get(c.unwrap()).copied().unwrap_or(u32::MAX)
                 }).collect();
                 Ok(Some(y))
             }, GetOutput::from_type(DataType::UInt32)).alias("chr_n")
         ])
         .sort_by_exprs(
-            vec![
-                col("chr_n"), 
-                col("align_0"), 
-                col("align_1"), 
-                col("region_0"), 
-                col("region_1")],
-            vec![false, false, false, false, false],
+            keys,
+            descending,
             false
         )
         .select([
-            col("*").exclude(&["chr_n"])
-        ]).collect()?;
-    println!("{:?}", result);
+            col("*").exclude(["chr_n"])
+        ])
+}
 
-    
-    let mut outfile = std::fs::File::create(abs_output_file.clone()).unwrap();
-    CsvWriter::new(&mut outfile).has_header(false).with_delimiter(b'\t').finish(&mut result)?;
-    println!("Results in \x1b[33m{}\x1b[m", abs_output_file.to_string_lossy().to_string());
-    
+fn progress_bar(bar: ProgressBar, template: &str) -> ProgressBar {
+    if !log_enabled!(Level::Info) {
+        return ProgressBar::hidden();
+    }
+    bar.with_style(ProgressStyle::with_template(template).unwrap())
+}
+
+// ticks when dropped, so a BED file counts as done however its screening ends
+struct Tick<'a>(&'a ProgressBar);
+
+impl Drop for Tick<'_> {
+    fn drop(&mut self) {
+        self.0.inc(1);
+    }
+}
+
+// invalid coordinates or a CIGAR that does not parse or does not end at the alignment end,
+// as clipped or supplementary records sometimes do, would fail every coverage computed later,
+// so such alignments are dropped and counted instead of aborting the run
+fn drop_inconsistent(merged: LazyFrame) -> PolarsResult<LazyFrame> {
+    let schema = merged.schema()?;
+    let checked = merged
+        .with_column(
+            as_struct(&[col("align_0"), col("align_1"), col("region_0"), col("region_1"), col("cigar")])
+            .map(|s| {
+                let ca = s.struct_()?;
+                let fields = ca.fields();
+                let errors: Utf8Chunked = fields[0].i64()?.into_iter()
+                    .zip(fields[1].i64()?)
+                    .zip(fields[2].i64()?)
+                    .zip(fields[3].i64()?)
+                    .zip(fields[4].utf8()?)
+                    .map(|((((a, b), c), d), cigar)| match (a, b, c, d, cigar) {
+                        (Some(a), Some(b), Some(c), Some(d), Some(cigar)) => calc_coverage(a, b, c, d, cigar, CoverageMetric::Total).err(),
+                        _ => None,
+                    })
+                    .collect();
+                Ok(Some(errors.into_series()))
+            }, GetOutput::from_type(DataType::Utf8))
+            .alias("cigar_error")
+        );
+    let inconsistent = checked.clone()
+        .filter(col("cigar_error").is_not_null())
+        .select([col("read"), col("cigar_error")])
+        .collect()?;
+    if inconsistent.height() > 0 {
+        warn!("Skipped {} alignments of {} reads with invalid coordinates or a CIGAR that does not match the alignment, e.g. {}",
+            inconsistent.height(),
+            inconsistent["read"].n_unique()?,
+            inconsistent["cigar_error"].utf8()?.get(0).unwrap_or_default());
+    }
+    Ok(checked
+        .filter(col("cigar_error").is_null())
+        .select(schema.iter_names().map(|name| col(name)).collect::<Vec<_>>()))
+}
+
+// first mapped alignment of every read in the BAM, unmapped records have no position to report
+fn read_all_alignments(samtools: &Path, align_file: &Path) -> ReadlocResult<DataFrame> {
+    let view = Command::new(samtools)
+        .args(["view", "-F", "4"]).arg(align_file)
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|source| ReadlocError::Io { op: "samtools view", path: align_file.to_path_buf(), source })?;
+    let sam = String::from_utf8_lossy(&view.stdout);
+    let mut chrs: Vec<&str> = vec![];
+    let mut starts: Vec<Option<i64>> = vec![];
+    let mut ends: Vec<Option<i64>> = vec![];
+    let mut reads: Vec<&str> = vec![];
+    let mut cigars: Vec<&str> = vec![];
+    let mut strands: Vec<Option<&str>> = vec![];
+    for line in sam.lines() {
+        let fields: Vec<&str> = line.splitn(7, '\t').collect();
+        if fields.len() < 6 {
+            continue;
+        }
+        let start = fields[3].parse::<i64>().ok().map(|pos| pos - 1);
+        chrs.push(fields[2]);
+        starts.push(start);
+        ends.push(start.and_then(|s| cigar_parser(fields[5], s).ok()).and_then(|r| r.last().map(|b| b.1)));
+        reads.push(fields[0]);
+        cigars.push(fields[5]);
+        strands.push(fields[1].parse::<u16>().ok().map(|flag| if flag & 16 != 0 { "-" } else { "+" }));
+    }
+    Ok(DataFrame::new(vec![
+        Series::new("chr", chrs),
+        Series::new("align_0", starts),
+        Series::new("align_1", ends),
+        Series::new("read", reads),
+        Series::new("cigar", cigars),
+        Series::new("strand", strands),
+    ])?.unique_stable(Some(&["read".to_string()]), UniqueKeepStrategy::First, None)?)
+}
+
+// reason is `assigned`, `filtered` when the read overlapped a region but was dropped
+// afterwards, or `no_overlap` when it never overlapped any region
+fn with_unassigned(result: DataFrame, samtools: &Path, align_file: &Path, merged: &LazyFrame, chr_map: HashMap<String, u32>, excluded: &[String]) -> ReadlocResult<DataFrame> {
+    let overlapped = merged.clone().select([col("read")]).collect()?["read"].clone();
+    let all_reads = read_all_alignments(samtools, align_file)?;
+    let mut unassigned = exclude_reads(all_reads.lazy(), excluded)
+        .filter(col("read").is_in(lit(result["read"].clone())).not())
+        .with_column(
+            when(col("read").is_in(lit(overlapped)))
+                .then(lit("filtered"))
+                .otherwise(lit("no_overlap"))
+                .alias("reason")
+        );
+    let schema = result.schema();
+    let columns: Vec<Expr> = schema.iter()
+        .map(|(name, dtype)| match name.as_str() {
+            "chr" | "align_0" | "align_1" | "read" | "cigar" | "strand" => col(name),
+            _ => lit(NULL).cast(dtype.clone()).alias(name),
+        })
+        .chain([col("reason")])
+        .collect();
+    unassigned = unassigned.select(columns);
+    let assigned = result.lazy().with_column(lit("assigned").alias("reason"));
+    Ok(sort_by_position(concat(&[assigned, unassigned], false, false)?, chr_map).collect()?)
+}
+
+// the `no_overlap` reads of --include-unassigned on their own, as chr, start, end, read, CIGAR and
+// strand; intergenic or unannotated signal, and a read missing from both outputs was lost on the way
+fn write_no_overlap(cli: &Cli, align_file: &Path, merged: &LazyFrame, chr_map: HashMap<String, u32>, path: &Path, excluded: &[String]) -> ReadlocResult<usize> {
+    let overlapped = merged.clone().select([col("read")]).collect()?["read"].clone();
+    let no_overlap = exclude_reads(read_all_alignments(&cli.samtools, align_file)?.lazy(), excluded)
+        .filter(col("read").is_in(lit(overlapped)).not())
+        .with_columns([
+            lit(NULL).cast(DataType::Int64).alias("region_0"),
+            lit(NULL).cast(DataType::Int64).alias("region_1"),
+            lit(NULL).cast(DataType::Utf8).alias("region"),
+        ]);
+    let no_overlap = sort_by_position(no_overlap, chr_map)
+        .select([col("chr"), col("align_0"), col("align_1"), col("read"), col("cigar"), col("strand")])
+        .collect()?;
+    let mut no_overlap = shift_starts(&no_overlap, cli.coord_base)?;
+    let mut outfile = fs::File::create(path)
+        .map_err(|source| ReadlocError::Io { op: "create", path: path.to_path_buf(), source })?;
+    CsvWriter::new(&mut outfile).has_header(false).with_delimiter(b'\t').finish(&mut no_overlap)?;
+    Ok(no_overlap.height())
+}
+
+fn to_tsv(df: &mut DataFrame) -> PolarsResult<Vec<u8>> {
+    let mut buf = vec![];
+    CsvWriter::new(&mut buf).has_header(false).with_delimiter(b'\t').finish(df)?;
+    Ok(buf)
+}
+
+// reruns the whole assignment in-process, the output must be byte-identical
+fn check_determinism(cli: &Cli, sample: &Sample, start_time: Instant, result: &mut DataFrame) -> ReadlocResult<()> {
+    info!("\x1b[45mRerunning {} to check determinism\x1b[m", sample.name);
+    let (mut rerun, _, _) = assign_reads(cli, sample, start_time)?;
+    let first = to_tsv(result)?;
+    let second = to_tsv(&mut rerun)?;
+    if first != second {
+        let first = String::from_utf8_lossy(&first);
+        let second = String::from_utf8_lossy(&second);
+        let mut first_lines = first.lines();
+        let mut second_lines = second.lines();
+        let mut row = 0;
+        loop {
+            row += 1;
+            match (first_lines.next(), second_lines.next()) {
+                (Some(x), Some(y)) if x == y => continue,
+                (x, y) => return Err(ReadlocError::Usage(format!("Output differs between runs at row {}:\n  first:  {}\n  second: {}",
+                    row, x.unwrap_or("<end of output>"), y.unwrap_or("<end of output>")))),
+            }
+        }
+    }
+    info!("\x1b[42mOutput is identical between runs\x1b[m");
     Ok(())
-}
\ No newline at end of file
+}
+
+enum BedOutcome {
+    Screened(Box<LazyFrame>),
+    /// over --per-bed-timeout
+    Skipped(String),
+    /// not started within --timeout-total
+    TimedOut(String),
+    /// no read overlaps the file
+    Empty(String),
+}
+
+// what screen_bed joins when no BED file has an overlap, so the rest of the pipeline
+// and the written output keep their usual columns
+fn empty_joined(cli: &Cli) -> PolarsResult<LazyFrame> {
+    let mut columns = vec![
+        Series::new_empty("chr", &DataType::Utf8), Series::new_empty("align_0", &DataType::Int64),
+        Series::new_empty("align_1", &DataType::Int64), Series::new_empty("read", &DataType::Utf8),
+        Series::new_empty("region_0", &DataType::Int64), Series::new_empty("region_1", &DataType::Int64),
+        Series::new_empty("region", &DataType::Utf8), Series::new_empty("cigar", &DataType::Utf8),
+        Series::new_empty("strand", &DataType::Utf8),
+    ];
+    if cli.qual_weight {
+        columns.push(Series::new_empty("qual", &DataType::Utf8));
+    }
+    if cli.needs_flag() {
+        columns.push(Series::new_empty("flag", &DataType::Int64));
+    }
+    if cli.umi_tag.is_some() {
+        columns.push(Series::new_empty("umi", &DataType::Utf8));
+    }
+    if cli.weight != Weight::None {
+        columns.push(Series::new_empty("weight", &DataType::Float64));
+    }
+    columns.push(Series::new_empty("source", &DataType::Utf8));
+    Ok(DataFrame::new(columns)?.lazy())
+}
+
+// every coverage below parses the `cigar` column, so the skips become deletions there and
+// the CIGAR of the read is kept in `read_cigar` until the output
+fn unsplit(merged: LazyFrame) -> LazyFrame {
+    merged
+        .with_column(col("cigar").alias("read_cigar"))
+        .with_column(col("cigar").map(|s| {
+            let cigars: Utf8Chunked = s.utf8()?.into_iter().map(|c| c.map(unsplit_cigar)).collect();
+            Ok(Some(cigars.into_series()))
+        }, GetOutput::same_type()))
+}
+
+// every grouping below is by the `read` column, so it is replaced by the key and the name is kept
+// in `read_name` until the output; names hold no whitespace, so a tab cannot make two keys equal
+fn with_read_key(cli: &Cli, merged: LazyFrame) -> LazyFrame {
+    let paired = cli.paired;
+    let read_key = cli.read_key;
+    let fields = match read_key {
+        // under --paired the key is the fragment, the name without its mate suffix
+        ReadKey::Name if paired => vec![col("read")],
+        ReadKey::Name => return merged,
+        ReadKey::Mate => vec![col("read"), col("flag")],
+        ReadKey::Alignment => vec![col("read"), col("chr"), col("align_0")],
+    };
+    let key = as_struct(&fields)
+        .map(move |s| {
+            let ca = s.struct_()?;
+            let fields = ca.fields();
+            let names = fields[0].utf8()?;
+            let key: Utf8Chunked = match read_key {
+                ReadKey::Name => names.into_iter()
+                    .map(|name| name.map(|name| name.strip_suffix("/1").or_else(|| name.strip_suffix("/2")).unwrap_or(name)))
+                    .collect(),
+                ReadKey::Mate => names.into_iter()
+                    .zip(fields[1].i64()?)
+                    .map(|(name, flag)| Some(format!("{}\t{}", name?, flag? & 0xC0)))
+                    .collect(),
+                _ => names.into_iter()
+                    .zip(fields[1].utf8()?)
+                    .zip(fields[2].i64()?)
+                    .map(|((name, chr), start)| Some(format!("{}\t{}\t{}", name?, chr?, start?)))
+                    .collect(),
+            };
+            Ok(Some(key.into_series()))
+        }, GetOutput::from_type(DataType::Utf8));
+    merged.with_columns([col("read").alias("read_name"), key.alias("read")])
+}
+
+// the read names back in place of the keys of with_read_key
+fn with_read_names(lf: LazyFrame) -> LazyFrame {
+    if !lf.schema().is_ok_and(|schema| schema.contains("read_name")) {
+        return lf;
+    }
+    lf.with_column(col("read_name").alias("read"))
+        .select([col("*").exclude(["read_name"])])
+}
+
+fn assign_reads(cli: &Cli, sample: &Sample, start_time: Instant) -> ReadlocResult<(DataFrame, bool, Option<DataFrame>)> {
+    let align_file = &sample.align_file;
+    let abs_region_dir = sample.region_dir.clone();
+    let mut timed_out = false;
+    let mut skipped: Vec<String> = vec![];
+    let mut timings: Vec<(String, &str, Duration)> = vec![];
+    let excluded = cli.exclude_reads.as_deref().map(read_name_list).transpose()?.unwrap_or_default();
+    let kept = cli.regions.as_deref().map(read_name_list).transpose()?.unwrap_or_default();
+
+    let mut merged: LazyFrame;
+    let mut disposition = Disposition::default();
+    let entries = fs::read_dir(&abs_region_dir)
+        .map_err(|source| ReadlocError::Io { op: "read directory", path: abs_region_dir.clone(), source })?;
+    // sorted so the concat order, and with it the output, does not depend on the directory or thread order
+    let mut bed_files: Vec<String> = entries.flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|file_name| is_region_file(Path::new(file_name)))
+        .collect();
+    bed_files.sort();
+    let n_bed = bed_files.len();
+    // one directory per sample, the alignments of a run share the BED file names
+    let sample_checkpoints = cli.checkpoint_dir.as_deref().map(|dir| dir.join(&sample.name));
+    if let Some(dir) = sample_checkpoints.as_deref() {
+        fs::create_dir_all(dir).map_err(|source| ReadlocError::Io { op: "create", path: dir.to_path_buf(), source })?;
+        check_checkpoint_options(cli, dir)?;
+    }
+    // serially and up front, so the reports of the files do not interleave and all precede the reads
+    if cli.annotation_qc {
+        for file_name in bed_files.iter() {
+            annotation_qc(&abs_region_dir.join(file_name), file_name)?;
+        }
+    }
+    // on stderr, so the status lines and anything piped from stdout stay clean
+    let progress = progress_bar(ProgressBar::new(n_bed as u64), "{bar:40} {pos}/{len} BED files, {elapsed} elapsed, ETA {eta}");
+    let children = Children::new(cli.timeout_total.map(|secs| start_time + Duration::from_secs(secs)));
+    let outcomes: Vec<(BedOutcome, Option<(String, Duration)>)> = bed_files.into_par_iter()
+        .map(|file_name| {
+            let _tick = Tick(&progress);
+            if children.timed_out() {
+                return Ok((BedOutcome::TimedOut(file_name), None));
+            }
+            let abs_region_path = abs_region_dir.join(&file_name);
+            // the intersect and the parsed frames, the join itself stays lazy and runs with the other files
+            let bed_start = Instant::now();
+            let outcome = match sample_checkpoints.as_deref() {
+                Some(dir) => screen_bed_checkpointed(cli, &children, align_file, &abs_region_path, &file_name, &dir.join(format!("{}.parquet", file_name)))?,
+                None => screen_bed(cli, &children, align_file, &abs_region_path, &file_name)?,
+            };
+            let elapsed = bed_start.elapsed();
+            debug!("Screened {} in {:.2?}", file_name, elapsed);
+            Ok((outcome, Some((file_name, elapsed))))
+        })
+        .collect::<ReadlocResult<_>>()?;
+    progress.finish();
+
+    let mut dfs = vec![];
+    let mut unfinished: Vec<String> = vec![];
+    let mut n_empty = 0;
+    for (outcome, timing) in outcomes {
+        // a file stopped by --timeout-total before it started has no timing
+        let status = match outcome {
+            BedOutcome::Screened(_) => "screened",
+            BedOutcome::Skipped(_) => "skipped",
+            BedOutcome::TimedOut(_) => "timed out",
+            BedOutcome::Empty(_) => "empty",
+        };
+        timings.extend(timing.map(|(file_name, elapsed)| (file_name, status, elapsed)));
+        match outcome {
+            BedOutcome::Screened(joined_df) => dfs.push(keep_regions(*joined_df, &kept)),
+            BedOutcome::Skipped(file_name) => {
+                warn!("Skipped {}, intersect took longer than {}s", file_name, cli.per_bed_timeout.unwrap());
+                skipped.push(file_name);
+            }
+            BedOutcome::TimedOut(file_name) => unfinished.push(file_name),
+            BedOutcome::Empty(file_name) => {
+                info!("No read overlaps {}, skipped", file_name);
+                n_empty += 1;
+            }
+        }
+    }
+    if !unfinished.is_empty() {
+        warn!("Time limit of {}s reached, skipped {}", cli.timeout_total.unwrap(), unfinished.join(", "));
+        timed_out = true;
+    }
+
+    if n_bed == 0 {
+        return Err(no_region_files(&abs_region_dir));
+    }
+    if dfs.is_empty() && n_empty == 0 && !timed_out {
+        return Err(ReadlocError::Invalid {
+            what: "region directory",
+            path: abs_region_dir.clone(),
+            reason: "every BED file was skipped by --per-bed-timeout".to_string(),
+        });
+    }
+    if timed_out && dfs.is_empty() && n_empty == 0 {
+        return Err(ReadlocError::TimedOut { region_dir: abs_region_dir.clone(), limit: cli.timeout_total.unwrap(), partial: false });
+    }
+    if dfs.is_empty() {
+        warn!("No read overlaps any region in {}, no read is assigned.", abs_region_dir.to_string_lossy());
+        dfs.push(empty_joined(cli)?);
+    }
+    // on the joined frames, polars pushes the filter on the read down into both sides of the join
+    merged = with_read_key(cli, exclude_reads(concat(&dfs, false, true)?, &excluded));
+    if cli.no_split {
+        merged = unsplit(merged);
+    }
+    disposition.total_reads = n_reads(&merged)?;
+    merged = drop_inconsistent(merged)?;
+    disposition.skipped_parse_error = disposition.total_reads - n_reads(&merged)?;
+    info!("\nshape: {:?} (unrefined)", merged.clone().collect()?.shape());
+
+    if cli.three_prime_only.is_some_and(|window| window < 1) {
+        return Err(ReadlocError::Usage("--three-prime-only needs a window of at least 1 base.".to_string()));
+    }
+    let resolved = progress_bar(ProgressBar::new_spinner(), "{spinner} {pos} ambiguous reads resolved, {elapsed} elapsed");
+    let resolved_tick = resolved.clone();
+    let opts = Options {
+        metric: cli.coverage_metric,
+        tie_break: cli.tie_break,
+        join_strategy: cli.join_strategy,
+        min_coverage: cli.min_coverage,
+        fraction: cli.metric == Metric::Fraction,
+        qual_weight: cli.qual_weight,
+        track: cli.weight_track.as_deref().map(read_weight_track).transpose()?,
+        three_prime: cli.three_prime_only,
+        // score = coverage * prior weight of the region, so near-ties lean to the expected regions
+        prior: cli.prior.as_deref().map(read_prior).transpose()?.unwrap_or_default(),
+        // unlike QUAL the weight differs between the alignments of a multi-mapped read, so it can move the arg_max
+        alignment_weight: cli.weight != Weight::None,
+        feature_priority: cli.feature_priority.clone(),
+        min_absolute_and_fraction: cli.min_absolute_and_fraction,
+        min_read_length: cli.min_read_length,
+        paired: cli.paired,
+        top_k: cli.top_k,
+        no_resolve: cli.no_resolve,
+        on_resolved: Some(Arc::new(move || resolved_tick.inc(1))),
+    };
+    let disambiguation = Disambiguation::new(merged, &opts)?;
+    let n_candidate_reads = n_reads(&disambiguation.candidates)?;
+    if let Some(report_file) = cli.report_candidates.as_deref() {
+        // the scores the arg_max picks from, one row per read and candidate region
+        let report = with_read_names(disambiguation.scorable.clone())
+            .with_column(disambiguation.score().alias("coverage"))
+            .select([col("read"), col("chr"), col("region_0"), col("region_1"), col("region"), col("coverage")])
+            .sort_by_exprs([col("read"), col("chr"), col("region_0"), col("region")], [false; 4], false)
+            .collect()?;
+        // the regions are written in the same convention as the output next to it
+        let mut report = shift_starts(&report, cli.coord_base)?;
+        let mut outfile = fs::File::create(report_file)
+            .map_err(|source| ReadlocError::Io { op: "create", path: report_file.to_path_buf(), source })?;
+        CsvWriter::new(&mut outfile).has_header(true).with_delimiter(b'\t').finish(&mut report)?;
+        info!("{} candidates of {} ambiguous reads in \x1b[33m{}\x1b[m", report.height(), report["read"].n_unique()?, report_file.to_string_lossy());
+    }
+
+    // every read ends up in exactly one of skipped, dropped, unique, resolved and unresolved
+    let n_uniq = n_reads(&disambiguation.unique)?;
+    disposition.ambiguous = n_reads(&disambiguation.ambiguous)?;
+    let n_dedup = n_reads(&disambiguation.resolved)?;
+    disposition.unique = n_reads(&disambiguation.strong(disambiguation.unique.clone())?)?;
+    disposition.resolved = n_reads(&disambiguation.strong(disambiguation.resolved.clone())?)?;
+    disposition.unresolved = disposition.ambiguous - n_dedup;
+    disposition.dropped_by_threshold = disposition.total_reads - disposition.skipped_parse_error - n_candidate_reads
+        + (n_uniq - disposition.unique) + (n_dedup - disposition.resolved);
+    disposition.report(cli.summary.as_deref())?;
+
+    let em = if cli.em {
+        Some(em_counts(&disambiguation.unique, &disambiguation.ambiguous, cli.coverage_metric, cli.em_iters, cli.em_tol, cli.seed)?)
+    } else {
+        None
+    };
+
+    let timing = timing_table(&timings)?;
+    if timing.height() > 0 {
+        info!("Time per BED file, slowest first:\n{:?}", timing);
+    }
+    if cli.stats_per_bed {
+        let stats = per_bed_stats(&disambiguation.candidates, &disambiguation.unique, &disambiguation.ambiguous, &disambiguation.resolved)?
+            .lazy()
+            .join(timing.lazy(), [col("source")], [col("source")], JoinType::Left)
+            .collect()?;
+        report!("{:?}", stats);
+        if !skipped.is_empty() {
+            report!("Skipped over the per-BED time limit: {}", skipped.join(", "));
+        }
+    }
+
+    let chr_map = match cli.chrom_order.as_deref() {
+        Some(path) => read_chrom_order(path)?,
+        None => {
+            let mut chr_map: HashMap<String, u32> = HashMap::new();
+            for i in 1..=22 {
+                let chr = format!("chr{}", i);
+                chr_map.entry(chr).or_insert(i);
+            }
+            chr_map.insert("chrX".to_string(), 97);
+            chr_map.insert("chrY".to_string(), 98);
+            chr_map.insert("chrM".to_string(), 99);
+            chr_map
+        }
+    };
+
+    let mut assigned = disambiguation.assigned()?;
+    // unique reads are scored like the ambiguous ones, so the column compares across both
+    if cli.with_coverage && cli.top_k.is_none() {
+        assigned = assigned.with_column(disambiguation.unweighted_score().alias("coverage"));
+    }
+    // the disposition counts ran the arg_max already, the spinner counts the final pass only
+    resolved.set_position(0);
+    let mut assigned = with_read_names(assigned);
+    if cli.no_split {
+        assigned = assigned.with_column(col("read_cigar").alias("cigar"));
+    }
+    let mut result = sort_by_position(assigned, chr_map.clone())
+        .select([{
+            let mut internal = vec!["qual", "flag", "read_cigar", "weight"];
+            if !cli.emit_source {
+                internal.push("source");
+            }
+            if cli.no_strand {
+                internal.push("strand");
+            }
+            col("*").exclude(internal)
+        }])
+        .collect()?;
+    resolved.finish();
+
+    // PCR duplicates of a molecule share its UMI and start, the first in output order stands
+    // for them; reads without the tag are all kept
+    if cli.umi_tag.is_some() {
+        let reads_before = result.height();
+        let first = col("read").first().over([col("umi"), col("region"), col("align_0")]);
+        result = result.lazy()
+            .filter(col("umi").is_null().or(col("read").eq(first)))
+            .collect()?
+            .drop("umi")?;
+        info!("Collapsed {} reads sharing a UMI, region and start position", reads_before - result.height());
+    }
+
+    if cli.collapse_position {
+        let reads_before = result.height();
+        result = result.lazy()
+            .unique_stable(Some(vec!["region".to_string(), "align_0".to_string()]), UniqueKeepStrategy::First)
+            .collect()?;
+        info!("Collapsed {} reads sharing region and start position", reads_before - result.height());
+    }
+
+    if let Some(min_reads) = cli.min_region_reads {
+        let reads_before = result.height();
+        let regions_before = result["region"].n_unique()?;
+        result = result.lazy()
+            .filter(col("read").count().over([col("region")]).gt_eq(lit(min_reads)))
+            .collect()?;
+        info!("Removed {} reads from {} regions with fewer than {} reads", 
+            reads_before - result.height(), 
+            regions_before - result["region"].n_unique()?, 
+            min_reads);
+    }
+    if let Some(unassigned_file) = cli.unassigned.as_deref() {
+        let n_unassigned = write_no_overlap(cli, align_file, &with_read_names(disambiguation.candidates.clone()), chr_map.clone(), unassigned_file, &excluded)?;
+        info!("{} reads without an overlapping region in \x1b[33m{}\x1b[m", n_unassigned, unassigned_file.to_string_lossy());
+    }
+    if cli.include_unassigned {
+        result = with_unassigned(result, &cli.samtools, align_file, &with_read_names(disambiguation.candidates.clone()), chr_map, &excluded)?;
+    }
+    if cli.emit_span {
+        let span: Vec<Option<i64>> = result["cigar"].utf8()?
+            .into_iter()
+            .map(|c| c.map(aligned_span).transpose())
+            .collect::<Result<_, _>>()
+            .map_err(|e| PolarsError::ComputeError(e.into()))?;
+        let mut span = Int64Chunked::from_iter(span).into_series();
+        span.rename("span");
+        result.with_column(span)?;
+    }
+    Ok((result, timed_out, em))
+}
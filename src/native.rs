@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::path::Path;
+use polars::prelude::*;
+use rust_htslib::bam::{self, Read};
+use locreadion::cigar_parser;
+use crate::{Region, Strand, Weight};
+
+// regions of one chromosome and strand sorted by start, with the running maximum of
+// their ends so a query can stop scanning as soon as no earlier region can reach it
+struct RegionIndex<'a> {
+    regions: Vec<&'a Region>,
+    max_end: Vec<i64>,
+}
+
+impl<'a> RegionIndex<'a> {
+    fn new(mut regions: Vec<&'a Region>) -> RegionIndex<'a> {
+        regions.sort_by_key(|r| (r.start, r.end));
+        let max_end = regions.iter()
+            .scan(i64::MIN, |max, r| {
+                *max = (*max).max(r.end);
+                Some(*max)
+            })
+            .collect();
+        RegionIndex { regions, max_end }
+    }
+
+    // indices of the regions overlapping [start, end), half-open like bedtools
+    fn overlapping(&self, start: i64, end: i64, hits: &mut Vec<usize>) {
+        let mut i = self.regions.partition_point(|r| r.start < end);
+        while i > 0 && self.max_end[i - 1] > start {
+            i -= 1;
+            if self.regions[i].end > start {
+                hits.push(i);
+            }
+        }
+    }
+}
+
+fn htslib_error(e: rust_htslib::errors::Error) -> PolarsError {
+    PolarsError::ComputeError(format!("failed to read BAM: {}", e).into())
+}
+
+// one row per read and region on the strand `strand` asks for and sharing at least one aligned block,
+// or any part of the alignment span without `split`, which is what `bedtools intersect -split -wo` with
+// the strand flag reports, alignments below `min_mapq`
+// are skipped and so are secondary and supplementary ones under `primary_only`; the string value
+// of `umi_tag` is kept as `umi`, null where the record has none, and the MAPQ or AS of `weight` as `weight`
+#[allow(clippy::too_many_arguments)]
+pub fn intersect(align_file: &Path, regions: &[Region], strand: Strand, split: bool, with_qual: bool, with_flag: bool, umi_tag: Option<&str>, weight: Weight, min_mapq: Option<u8>, primary_only: bool) -> PolarsResult<DataFrame> {
+    // regions are keyed by the strand a read must be on to reach them
+    let mut grouped: HashMap<(&str, &str), Vec<&Region>> = HashMap::new();
+    for region in regions {
+        let read_strand = match (strand, region.strand.as_str()) {
+            (Strand::Ignore, _) => "",
+            (Strand::Opposite, "+") => "-",
+            (Strand::Opposite, "-") => "+",
+            (_, region_strand) => region_strand,
+        };
+        grouped.entry((region.chr.as_str(), read_strand)).or_default().push(region);
+    }
+    let index: HashMap<(&str, &str), RegionIndex> = grouped.into_iter()
+        .map(|(key, regions)| (key, RegionIndex::new(regions)))
+        .collect();
+
+    let mut reader = bam::Reader::from_path(align_file).map_err(htslib_error)?;
+    let header = reader.header().clone();
+    let (mut chr, mut align_0, mut align_1, mut read) = (vec![], vec![], vec![], vec![]);
+    let (mut region_0, mut region_1, mut region, mut cigar) = (vec![], vec![], vec![], vec![]);
+    let (mut strands, mut qual, mut flag, mut umi) = (vec![], vec![], vec![], vec![]);
+    let mut weights: Vec<f64> = vec![];
+    let mut hits: Vec<usize> = vec![];
+    for record in reader.records() {
+        let record = record.map_err(htslib_error)?;
+        if record.is_unmapped() || record.tid() < 0 {
+            continue;
+        }
+        if min_mapq.is_some_and(|min_mapq| record.mapq() < min_mapq) {
+            continue;
+        }
+        if primary_only && (record.is_secondary() || record.is_supplementary()) {
+            continue;
+        }
+        let contig = String::from_utf8_lossy(header.tid2name(record.tid() as u32)).to_string();
+        let read_strand = match strand {
+            Strand::Ignore => "",
+            _ if record.is_reverse() => "-",
+            _ => "+",
+        };
+        let Some(regions) = index.get(&(contig.as_str(), read_strand)) else {
+            continue;
+        };
+        let cigar_view = record.cigar();
+        let cigar_string = cigar_view.to_string();
+        let alignment_end = cigar_view.end_pos();
+        let blocks = if split {
+            cigar_parser(&cigar_string, record.pos())
+                .map_err(|e| PolarsError::ComputeError(e.into()))?
+        } else {
+            vec![(record.pos(), alignment_end)]
+        };
+        hits.clear();
+        for &(start, end) in blocks.iter() {
+            regions.overlapping(start, end, &mut hits);
+        }
+        hits.sort_unstable();
+        hits.dedup();
+        if hits.is_empty() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(record.qname()).to_string();
+        let record_qual: String = if record.qual().first().is_none_or(|&q| q == 255) {
+            "*".to_string()
+        } else {
+            // capped at 93 (`~`) like the SAM text samtools writes, higher stored values would overflow
+            record.qual().iter().map(|&q| (q.min(93) + 33) as char).collect()
+        };
+        let record_umi = umi_tag.and_then(|tag| match record.aux(tag.as_bytes()) {
+            Ok(bam::record::Aux::String(value)) => Some(value.to_string()),
+            _ => None,
+        });
+        // the same as the bedtools path, MAPQ 255 and a missing or negative AS count as 0
+        let record_weight = match weight {
+            Weight::None => 1.0,
+            Weight::Mapq if record.mapq() == 255 => 0.0,
+            Weight::Mapq => record.mapq() as f64,
+            Weight::As => match record.aux(b"AS") {
+                Ok(bam::record::Aux::I8(score)) => score as f64,
+                Ok(bam::record::Aux::U8(score)) => score as f64,
+                Ok(bam::record::Aux::I16(score)) => score as f64,
+                Ok(bam::record::Aux::U16(score)) => score as f64,
+                Ok(bam::record::Aux::I32(score)) => score as f64,
+                Ok(bam::record::Aux::U32(score)) => score as f64,
+                _ => 0.0,
+            }.max(0.0),
+        };
+        for &hit in hits.iter() {
+            let hit = regions.regions[hit];
+            chr.push(contig.clone());
+            align_0.push(record.pos());
+            align_1.push(alignment_end);
+            read.push(name.clone());
+            region_0.push(hit.start);
+            region_1.push(hit.end);
+            region.push(hit.name.clone());
+            cigar.push(cigar_string.clone());
+            strands.push(if record.is_reverse() { "-" } else { "+" });
+            if with_qual {
+                qual.push(record_qual.clone());
+            }
+            if with_flag {
+                flag.push(record.flags() as i64);
+            }
+            if umi_tag.is_some() {
+                umi.push(record_umi.clone());
+            }
+            if weight != Weight::None {
+                weights.push(record_weight);
+            }
+        }
+    }
+
+    let mut columns = vec![
+        Series::new("chr", chr), Series::new("align_0", align_0), Series::new("align_1", align_1),
+        Series::new("read", read), Series::new("region_0", region_0), Series::new("region_1", region_1),
+        Series::new("region", region), Series::new("cigar", cigar), Series::new("strand", strands),
+    ];
+    if with_qual {
+        columns.push(Series::new("qual", qual));
+    }
+    if with_flag {
+        columns.push(Series::new("flag", flag));
+    }
+    if umi_tag.is_some() {
+        columns.push(Series::new("umi", umi));
+    }
+    if weight != Weight::None {
+        columns.push(Series::new("weight", weights));
+    }
+    DataFrame::new(columns)
+}
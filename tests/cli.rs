@@ -0,0 +1,1136 @@
+//! End-to-end runs of the binary against stand-in `bedtools` and `samtools` scripts that
+//! replay canned intersect output, so neither tool nor a real alignment file is needed.
+#![cfg(unix)]
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, SystemTime};
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use locreadion::{Options, disambiguate_reads};
+use polars::prelude::*;
+use tempfile::TempDir;
+
+// `bedtools intersect -b <bed>` prints `data/<bed>.bed` under -bed and `data/<bed>.sam`
+// under -ubam, which the pipeline hands to `samtools view -`; -s and -S keep the -bed lines
+// whose read strand (column 6) is the same as or differs from the region strand (column 18),
+// a `data/<bed>.sleep` file makes the call hang for that many seconds instead, and every
+// call but --version is logged to `bedtools.log`
+const BEDTOOLS: &str = r#"#!/bin/sh
+[ "$1" = --version ] && { echo "bedtools v2.31.0"; exit 0; }
+echo "$*" >> DATA/../bedtools.log
+b=; mode=; strand=
+while [ $# -gt 0 ]; do
+    case "$1" in -b) b=$(basename "$2"); shift;; -s) strand='$6 == $18';; -S) strand='$6 != $18';; -ubam) mode=sam;; -bed) mode=bed;; esac
+    shift
+done
+[ -n "$b" ] || exit 0
+[ -f "DATA/$b.sleep" ] && exec sleep "$(cat "DATA/$b.sleep")"
+if [ $mode = bed ] && [ -n "$strand" ]; then
+    awk -F '\t' "$strand" "DATA/$b.bed" 2>/dev/null
+else
+    cat "DATA/$b.$mode" 2>/dev/null
+fi
+exit 0
+"#;
+
+const SAMTOOLS: &str = r#"#!/bin/sh
+[ "$1" = --version ] && { echo "samtools 1.17"; exit 0; }
+[ "$1" = view ] || exit 0
+for last; do :; done
+if [ "$last" = - ]; then cat; else cat DATA/all.sam 2>/dev/null; fi
+exit 0
+"#;
+
+struct Fixture {
+    dir: TempDir,
+}
+
+impl Fixture {
+    fn new() -> Fixture {
+        let dir = TempDir::new().unwrap();
+        for sub in ["bin", "data", "regions", "out"] {
+            fs::create_dir(dir.path().join(sub)).unwrap();
+        }
+        fs::write(dir.path().join("sample.bam"), "").unwrap();
+        let data = dir.path().join("data");
+        for (name, script) in [("bedtools", BEDTOOLS), ("samtools", SAMTOOLS)] {
+            let path = dir.path().join("bin").join(name);
+            fs::write(&path, script.replace("DATA", data.to_str().unwrap())).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        Fixture { dir }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    fn write(&self, name: &str, content: &str) -> PathBuf {
+        let path = self.path(name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// A region file and the intersect output the stand-in bedtools gives for it, in
+    /// BED12 + region fields and SAM lines.
+    fn bed(&self, name: &str, regions: &str, overlaps: &str, sam: &str) {
+        self.write(&format!("regions/{}", name), regions);
+        self.write(&format!("data/{}.bed", name), overlaps);
+        self.write(&format!("data/{}.sam", name), sam);
+    }
+
+    /// The binary with the stand-in tools and the output directory, but no inputs.
+    fn command(&self) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_locreadion"));
+        command
+            .arg("-o").arg(self.path("out"))
+            .arg("--bedtools").arg(self.path("bin/bedtools"))
+            .arg("--samtools").arg(self.path("bin/samtools"))
+            .env_remove("RUST_LOG");
+        command
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        self.command()
+            .arg("-a").arg(self.path("sample.bam"))
+            .arg("-r").arg(self.path("regions"))
+            .args(args)
+            .output()
+            .unwrap()
+    }
+
+    fn run_ok(&self, args: &[&str]) -> Output {
+        let output = self.run(args);
+        assert!(output.status.success(), "locreadion {:?} failed:\n{}", args, String::from_utf8_lossy(&output.stderr));
+        output
+    }
+
+    /// The arguments of every intersect the stand-in bedtools ran, in order.
+    fn intersects(&self) -> Vec<String> {
+        fs::read_to_string(self.path("bedtools.log")).unwrap_or_default().lines().map(str::to_string).collect()
+    }
+
+    /// The region files screened, by the file name after -b of every -bed intersect, sorted
+    /// since the files are screened in parallel.
+    fn screened(&self) -> Vec<String> {
+        let mut files: Vec<String> = self.intersects().iter()
+            .filter(|args| args.ends_with("-bed"))
+            .map(|args| args.split(' ').skip_while(|&arg| arg != "-b").nth(1).unwrap().rsplit('/').next().unwrap().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// (read, region) of every line of the result.
+    fn assigned(&self) -> Vec<(String, String)> {
+        read(&self.path("out/sample.reloc.bed")).lines()
+            .map(|line| {
+                let fields: Vec<&str> = line.split('\t').collect();
+                (fields[3].to_string(), fields[6].to_string())
+            })
+            .collect()
+    }
+}
+
+fn read(path: &Path) -> String {
+    fs::read_to_string(path).unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e))
+}
+
+// the counts of a --summary file by key
+fn summary(path: &Path) -> HashMap<String, usize> {
+    read(path).trim().trim_start_matches('{').trim_end_matches('}')
+        .split(", ")
+        .map(|pair| {
+            let (key, n) = pair.split_once(": ").unwrap();
+            (key.trim_matches('"').to_string(), n.parse().unwrap())
+        })
+        .collect()
+}
+
+fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+    expected.iter().map(|&(read, region)| (read.to_string(), region.to_string())).collect()
+}
+
+// r1 aligns to chr1 with MAPQ 10 and to chr2 with MAPQ 40, covering a region of each completely
+fn multi_mapped(fixture: &Fixture) {
+    fixture.bed("a.bed", "chr1\t100\t150\tA1\t0\t+\n",
+        "chr1\t100\t150\tr1\t10\t+\t100\t150\t0,0,0\t1\t50\t0\tchr1\t100\t150\tA1\t0\t+\t50\n",
+        "r1\t0\tchr1\t101\t10\t50M\t*\t0\t0\t*\t*\n");
+    fixture.bed("b.bed", "chr2\t100\t150\tB1\t0\t+\n",
+        "chr2\t100\t150\tr1\t40\t+\t100\t150\t0,0,0\t1\t50\t0\tchr2\t100\t150\tB1\t0\t+\t50\n",
+        "r1\t256\tchr2\t101\t40\t50M\t*\t0\t0\t*\t*\n");
+}
+
+// r1 overlaps a region of each file and covers A1 best, r2 is spliced, r3 unique
+const A_OVERLAPS: &str = "chr1\t100\t150\tr1\t60\t+\t100\t150\t0,0,0\t1\t50\t0\tchr1\t120\t200\tA1\t0\t+\t30
+chr1\t300\t450\tr2\t60\t+\t300\t450\t0,0,0\t2\t20,30\t0,120\tchr1\t310\t330\tA2\t0\t+\t10
+";
+const B_OVERLAPS: &str = "chr1\t100\t150\tr1\t60\t+\t100\t150\t0,0,0\t1\t50\t0\tchr1\t90\t125\tB1\t0\t+\t25
+chr2\t1000\t1040\tr3\t60\t+\t1000\t1040\t0,0,0\t1\t40\t0\tchr2\t1000\t1100\tB2\t0\t+\t40
+";
+const R1: &str = "r1\t0\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*\n";
+const R2: &str = "r2\t0\tchr1\t301\t60\t20M100N30M\t*\t0\t0\t*\t*\n";
+const R3: &str = "r3\t0\tchr2\t1001\t60\t40M\t*\t0\t0\t*\t*\n";
+// mapped outside every region, in the BAM only
+const R4: &str = "r4\t0\tchr3\t11\t60\t30M\t*\t0\t0\t*\t*\n";
+const R5: &str = "r5\t16\tchr3\t21\t60\t30M\t*\t0\t0\t*\t*\n";
+
+fn overlapping(fixture: &Fixture) {
+    fixture.bed("a.bed", "chr1\t120\t200\tA1\t0\t+\nchr1\t310\t330\tA2\t0\t+\n", A_OVERLAPS, &[R1, R2].concat());
+    fixture.bed("b.bed", "chr1\t90\t125\tB1\t0\t+\nchr2\t1000\t1100\tB2\t0\t+\n", B_OVERLAPS, &[R1, R3].concat());
+}
+
+// the regions of `overlapping` and r7, on the other strand than A1
+fn antisense(fixture: &Fixture) {
+    overlapping(fixture);
+    fixture.bed("a.bed", "chr1\t120\t200\tA1\t0\t+\nchr1\t310\t330\tA2\t0\t+\n",
+        &[A_OVERLAPS, "chr1\t150\t190\tr7\t60\t-\t150\t190\t0,0,0\t1\t40\t0\tchr1\t120\t200\tA1\t0\t+\t40\n"].concat(),
+        &[R1, R2, "r7\t16\tchr1\t151\t60\t40M\t*\t0\t0\t*\t*\n"].concat());
+}
+
+// the strand flags passed to the intersects
+fn strand_flags(fixture: &Fixture) -> Vec<String> {
+    let mut flags: Vec<String> = fixture.intersects().iter()
+        .flat_map(|args| args.split(' ').filter(|&arg| arg == "-s" || arg == "-S").map(str::to_string).collect::<Vec<_>>())
+        .collect();
+    flags.dedup();
+    flags
+}
+
+fn frame(tsv: &str) -> DataFrame {
+    CsvReader::new(Cursor::new(tsv.as_bytes().to_vec()))
+        .has_header(false)
+        .with_delimiter(b'\t')
+        .finish()
+        .unwrap()
+}
+
+#[test]
+fn library_matches_the_cli() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    fixture.run_ok(&[]);
+    let expected = pairs(&[("r1", "A1"), ("r2", "A2"), ("r3", "B2")]);
+    assert_eq!(fixture.assigned(), expected);
+
+    // the fields the CLI parses of the intersect output
+    let bed_df = |tsv| frame(tsv).select(["column_1", "column_2", "column_3", "column_4", "column_6", "column_14", "column_15", "column_16"]).unwrap();
+    let bam_df = frame(&[R1, R2, R3].concat()).select(["column_1", "column_3", "column_4", "column_6"]).unwrap();
+    let library = |opts: &Options| {
+        let result = disambiguate_reads(bam_df.clone(), vec![bed_df(A_OVERLAPS), bed_df(B_OVERLAPS)], opts).unwrap();
+        let mut assigned: Vec<(String, String)> = result["read"].utf8().unwrap().into_iter()
+            .zip(result["region"].utf8().unwrap())
+            .map(|(read, region)| (read.unwrap().to_string(), region.unwrap().to_string()))
+            .collect();
+        assigned.sort();
+        assigned
+    };
+    assert_eq!(library(&Options::default()), expected);
+
+    // B1 is the shorter region, r1 fills more of it
+    fixture.run_ok(&["--metric", "fraction"]);
+    let expected = pairs(&[("r1", "B1"), ("r2", "A2"), ("r3", "B2")]);
+    assert_eq!(fixture.assigned(), expected);
+    assert_eq!(library(&Options { fraction: true, ..Options::default() }), expected);
+}
+
+#[test]
+fn weight_mapq_breaks_equal_overlaps() {
+    let fixture = Fixture::new();
+    multi_mapped(&fixture);
+    // the overlaps alone are tied
+    fixture.run_ok(&["--tie-break", "drop"]);
+    assert_eq!(fixture.assigned(), pairs(&[]));
+    fixture.run_ok(&["--tie-break", "drop", "--weight", "mapq"]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "B1")]));
+    fixture.run_ok(&["--top-k", "1", "--weight", "mapq"]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "B1")]));
+}
+
+#[test]
+fn top_k_refuses_per_read_counts() {
+    let fixture = Fixture::new();
+    multi_mapped(&fixture);
+    // every read keeps up to k rows, counting or deduplicating them would count a read k times
+    for flag in [&["--cpm"][..], &["--min-region-reads", "2"], &["--collapse-position"], &["--umi-tag", "UB"]] {
+        let output = fixture.run(&[&["--top-k", "2"][..], flag].concat());
+        assert_eq!(output.status.code(), Some(2), "{:?}", flag);
+        let log = String::from_utf8_lossy(&output.stderr);
+        assert!(log.contains("the argument '--top-k <N>' cannot be used with"), "{}", log);
+    }
+    fixture.run_ok(&["--top-k", "2"]);
+    assert_eq!(fixture.assigned().len(), 2);
+}
+
+#[test]
+fn no_resolve_refuses_region_counts() {
+    let fixture = Fixture::new();
+    multi_mapped(&fixture);
+    // r1 keeps a row in both A1 and B1, each region would count it
+    let counts = fixture.path("counts.tsv");
+    for flag in [&["--cpm"][..], &["--counts", counts.to_str().unwrap()]] {
+        let output = fixture.run(&[&["--no-resolve"][..], flag].concat());
+        assert_eq!(output.status.code(), Some(2), "{:?}", flag);
+        let log = String::from_utf8_lossy(&output.stderr);
+        assert!(log.contains("the argument '--no-resolve' cannot be used with"), "{}", log);
+    }
+    assert!(!counts.exists());
+    fixture.run_ok(&["--no-resolve"]);
+    assert_eq!(fixture.assigned().len(), 2);
+}
+
+#[test]
+fn cpm_divides_by_the_assigned_reads() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    fixture.run_ok(&["--cpm"]);
+    assert_eq!(read(&fixture.path("out/sample.counts.tsv")), "region\tcount\tcpm\nA1\t1\t333333.3333333333\nA2\t1\t333333.3333333333\nB2\t1\t333333.3333333333\n");
+}
+
+#[test]
+fn empty_regions_carry_the_namespace() {
+    let fixture = Fixture::new();
+    multi_mapped(&fixture);
+    fixture.write("regions/b.bed", "chr2\t100\t150\tB1\t0\t+\nchr2\t500\t600\tB2\t0\t+\n");
+    let empty = fixture.path("empty.bed");
+    fixture.run_ok(&["--namespace-regions", "--weight", "mapq", "--empty-regions", empty.to_str().unwrap()]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "b:B1")]));
+    assert_eq!(read(&empty), "chr1\t100\t150\ta:A1\t0\t+\nchr2\t500\t600\tb:B2\t0\t+\n");
+    // --regions names the prefixed labels too
+    let kept = fixture.write("kept.txt", "a:A1\nb:B2\n");
+    fixture.run_ok(&["--namespace-regions", "--regions", kept.to_str().unwrap(), "--empty-regions", empty.to_str().unwrap()]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "a:A1")]));
+    assert_eq!(read(&empty), "chr2\t500\t600\tb:B2\t0\t+\n");
+}
+
+#[test]
+fn platform_fills_in_unset_thresholds_only() {
+    let fixture = Fixture::new();
+    multi_mapped(&fixture);
+    // both overlaps are 50 bases, below the long-read --min-coverage of 100
+    let output = fixture.run_ok(&["--platform", "long"]);
+    assert_eq!(fixture.assigned(), pairs(&[]));
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("Applied defaults for long reads: --min-coverage 100, --min-read-length 500, --join-strategy parallel"), "{}", log);
+    let output = fixture.run_ok(&["--platform", "long", "--min-coverage", "10", "--min-read-length", "10", "--weight", "mapq"]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "B1")]));
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("Applied defaults for long reads: --join-strategy parallel"), "{}", log);
+}
+
+#[test]
+fn errors_remove_the_buffered_stdin() {
+    let fixture = Fixture::new();
+    let tmp_dir = fixture.path("tmp");
+    fs::create_dir(&tmp_dir).unwrap();
+    // the regions are buffered before the alignments are checked
+    let mut child = fixture.command()
+        .arg("-a").arg(fixture.path("missing.bam"))
+        .args(["-r", "-", "--tmp-dir"]).arg(&tmp_dir)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"chr1\t100\t150\tA1\t0\t+\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("missing.bam do not exist"));
+    assert_eq!(fs::read_dir(&tmp_dir).unwrap().count(), 0);
+}
+
+#[test]
+fn bed4_under_strand_same_fails_at_startup() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    fixture.write("regions/b.bed", "chr1\t90\t125\tB1\nchr2\t1000\t1100\tB2\n");
+    let output = fixture.run(&["--strand", "same"]);
+    assert_eq!(output.status.code(), Some(1));
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("b.bed: 4 columns on region line 1, --strand same needs the strand in column 6; pass --strand ignore"), "{}", log);
+    assert!(!log.contains("Start screening"), "{}", log);
+    assert!(!fixture.path("out/sample.reloc.bed").exists());
+    fixture.run_ok(&["--strand", "ignore"]);
+}
+
+#[test]
+fn chrom_order_puts_unlisted_contigs_last() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    let unplaced = "chrUn_1\t10\t40\tr4\t60\t+\t10\t40\t0,0,0\t1\t30\t0\tchrUn_1\t0\t100\tU1\t0\t+\t30\n";
+    fixture.bed("b.bed", "chr1\t90\t125\tB1\t0\t+\nchr2\t1000\t1100\tB2\t0\t+\nchrUn_1\t0\t100\tU1\t0\t+\n",
+        &[B_OVERLAPS, unplaced].concat(), &[R1, R3, "r4\t0\tchrUn_1\t11\t60\t30M\t*\t0\t0\t*\t*\n"].concat());
+    let order = fixture.write("order.tsv", "# contig\trank\nchr2\t1\nchr1\t2\n");
+    fixture.run_ok(&["--chrom-order", order.to_str().unwrap()]);
+    assert_eq!(fixture.assigned(), pairs(&[("r3", "B2"), ("r1", "A1"), ("r2", "A2"), ("r4", "U1")]));
+    // the default order knows chr1 to chrM only
+    fixture.run_ok(&[]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r2", "A2"), ("r3", "B2"), ("r4", "U1")]));
+}
+
+#[test]
+fn checkpoints_resume_and_refuse_other_options() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    let checkpoints = fixture.path("checkpoints");
+    let checkpoint_dir = checkpoints.to_str().unwrap();
+    fixture.run_ok(&["--checkpoint-dir", checkpoint_dir]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r2", "A2"), ("r3", "B2")]));
+    assert!(checkpoints.join("sample/a.bed.parquet").is_file());
+
+    // a.bed would now give no reads and b.bed loses r3, only b.bed is screened again
+    fixture.write("data/a.bed.bed", "");
+    fixture.write("data/b.bed.bed", B_OVERLAPS.lines().next().unwrap());
+    fs::remove_file(checkpoints.join("sample/b.bed.parquet")).unwrap();
+    let output = fixture.run_ok(&["--checkpoint-dir", checkpoint_dir]);
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("Resuming a.bed") && !log.contains("Resuming b.bed"), "{}", log);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r2", "A2")]));
+
+    let output = fixture.run(&["--checkpoint-dir", checkpoint_dir, "--weight", "mapq"]);
+    assert_eq!(output.status.code(), Some(1));
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("written with other values than --weight mapq, remove it"), "{}", log);
+}
+
+#[test]
+fn excluded_reads_are_in_no_output() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    fixture.write("data/all.sam", &[R1, R2, R3, R4, R5].concat());
+    let (counts, summary_file, unassigned) = (fixture.path("counts.tsv"), fixture.path("summary.json"), fixture.path("unassigned.bed"));
+    let outputs = [
+        "--counts", counts.to_str().unwrap(),
+        "--summary", summary_file.to_str().unwrap(),
+        "--unassigned", unassigned.to_str().unwrap(),
+    ];
+    fixture.run_ok(&outputs);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r2", "A2"), ("r3", "B2")]));
+    assert_eq!(summary(&summary_file)["total_reads"], 3);
+    assert_eq!(read(&unassigned), "chr3\t10\t40\tr4\t30M\t+\nchr3\t20\t50\tr5\t30M\t-\n");
+
+    // r1 overlaps regions of both files, r4 none
+    let excluded = fixture.write("excluded.txt", "r1\nr4\n");
+    fixture.run_ok(&[&outputs[..], &["--exclude-reads", excluded.to_str().unwrap()]].concat());
+    assert_eq!(fixture.assigned(), pairs(&[("r2", "A2"), ("r3", "B2")]));
+    assert_eq!(read(&counts), "region\tcount\nA2\t1\nB2\t1\n");
+    assert_eq!(summary(&summary_file)["total_reads"], 2);
+    assert_eq!(read(&unassigned), "chr3\t20\t50\tr5\t30M\t-\n");
+}
+
+#[test]
+fn min_coverage_keeps_reads_at_the_threshold() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    let summary_file = fixture.path("summary.json");
+    // the best coverage of r1 is 30 bases over A1, r2 covers A2 by 10 and r3 B2 by 40
+    for (threshold, expected, dropped) in [
+        ("29", pairs(&[("r1", "A1"), ("r3", "B2")]), 1),
+        ("30", pairs(&[("r1", "A1"), ("r3", "B2")]), 1),
+        ("31", pairs(&[("r3", "B2")]), 2),
+    ] {
+        fixture.run_ok(&["--min-coverage", threshold, "--summary", summary_file.to_str().unwrap()]);
+        assert_eq!(fixture.assigned(), expected, "--min-coverage {}", threshold);
+        assert_eq!(summary(&summary_file)["dropped_by_threshold"], dropped, "--min-coverage {}", threshold);
+    }
+}
+
+#[test]
+fn min_mapq_makes_multi_mappers_unique() {
+    let fixture = Fixture::new();
+    // r1 aligns to A1 with MAPQ 0 and to B1 with MAPQ 30, both alignments of r2 have MAPQ 0
+    fixture.bed("a.bed", "chr1\t100\t150\tA1\t0\t+\n",
+        "chr1\t100\t150\tr1\t0\t+\t100\t150\t0,0,0\t1\t50\t0\tchr1\t100\t150\tA1\t0\t+\t50
+chr1\t100\t150\tr2\t0\t+\t100\t150\t0,0,0\t1\t50\t0\tchr1\t100\t150\tA1\t0\t+\t50\n",
+        "r1\t0\tchr1\t101\t0\t50M\t*\t0\t0\t*\t*\nr2\t0\tchr1\t101\t0\t50M\t*\t0\t0\t*\t*\n");
+    fixture.bed("b.bed", "chr2\t100\t150\tB1\t0\t+\n",
+        "chr2\t100\t150\tr1\t30\t+\t100\t150\t0,0,0\t1\t50\t0\tchr2\t100\t150\tB1\t0\t+\t50
+chr2\t100\t150\tr2\t0\t+\t100\t150\t0,0,0\t1\t50\t0\tchr2\t100\t150\tB1\t0\t+\t50\n",
+        "r1\t256\tchr2\t101\t30\t50M\t*\t0\t0\t*\t*\nr2\t256\tchr2\t101\t0\t50M\t*\t0\t0\t*\t*\n");
+    let (counts, summary_file) = (fixture.path("counts.tsv"), fixture.path("summary.json"));
+    let outputs = ["--counts", counts.to_str().unwrap(), "--summary", summary_file.to_str().unwrap()];
+    fixture.run_ok(&outputs);
+    let disposition = summary(&summary_file);
+    assert_eq!((disposition["total_reads"], disposition["unique"], disposition["ambiguous"]), (2, 0, 2));
+
+    fixture.run_ok(&[&outputs[..], &["--min-mapq", "1"]].concat());
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "B1")]));
+    let disposition = summary(&summary_file);
+    assert_eq!((disposition["total_reads"], disposition["unique"], disposition["ambiguous"]), (1, 1, 0));
+    assert_eq!(read(&counts), "region\tcount\nB1\t1\n");
+}
+
+#[test]
+fn gzipped_region_files_are_screened() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    let regions = fs::read(fixture.path("regions/b.bed")).unwrap();
+    fs::remove_file(fixture.path("regions/b.bed")).unwrap();
+    let mut gzipped = GzEncoder::new(fs::File::create(fixture.path("regions/b.bed.gz")).unwrap(), Compression::default());
+    gzipped.write_all(&regions).unwrap();
+    gzipped.finish().unwrap();
+    fs::rename(fixture.path("data/b.bed.bed"), fixture.path("data/b.bed.gz.bed")).unwrap();
+    fs::rename(fixture.path("data/b.bed.sam"), fixture.path("data/b.bed.gz.sam")).unwrap();
+    fixture.run_ok(&[]);
+    assert_eq!(fixture.screened(), ["a.bed", "b.bed.gz"]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r2", "A2"), ("r3", "B2")]));
+}
+
+#[test]
+fn counts_match_the_output() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    // a second read of B2
+    fixture.bed("b.bed", "chr1\t90\t125\tB1\t0\t+\nchr2\t1000\t1100\tB2\t0\t+\n",
+        &[B_OVERLAPS, "chr2\t1050\t1080\tr6\t60\t+\t1050\t1080\t0,0,0\t1\t30\t0\tchr2\t1000\t1100\tB2\t0\t+\t30\n"].concat(),
+        &[R1, R3, "r6\t0\tchr2\t1051\t60\t30M\t*\t0\t0\t*\t*\n"].concat());
+    let counts = fixture.path("counts.tsv");
+    fixture.run_ok(&["--counts", counts.to_str().unwrap()]);
+    let mut rows: HashMap<String, usize> = HashMap::new();
+    for (_, region) in fixture.assigned() {
+        *rows.entry(region).or_default() += 1;
+    }
+    let mut expected: Vec<(String, usize)> = rows.into_iter().collect();
+    expected.sort();
+    let expected: String = expected.iter().map(|(region, n)| format!("{}\t{}\n", region, n)).collect();
+    assert_eq!(read(&counts), format!("region\tcount\n{}", expected));
+
+    // A1 is 80 bases long, A2 20 and B2 100
+    fixture.run_ok(&["--counts", counts.to_str().unwrap(), "--with-coverage"]);
+    assert_eq!(read(&counts), "region\tcount\tlength\tcount_per_kb\nA1\t1\t80\t12.5\nA2\t1\t20\t50.0\nB2\t2\t100\t20.0\n");
+}
+
+#[test]
+fn strand_modes_pick_the_overlapping_reads() {
+    let fixture = Fixture::new();
+    antisense(&fixture);
+    fixture.run_ok(&[]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r2", "A2"), ("r3", "B2")]));
+    assert_eq!(strand_flags(&fixture), ["-s"]);
+
+    fs::remove_file(fixture.path("bedtools.log")).unwrap();
+    fixture.run_ok(&["--strand", "opposite"]);
+    assert_eq!(fixture.assigned(), pairs(&[("r7", "A1")]));
+    assert_eq!(strand_flags(&fixture), ["-S"]);
+
+    fs::remove_file(fixture.path("bedtools.log")).unwrap();
+    fixture.run_ok(&["--strand", "ignore"]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r7", "A1"), ("r2", "A2"), ("r3", "B2")]));
+    assert!(strand_flags(&fixture).is_empty(), "{:?}", fixture.intersects());
+}
+
+#[test]
+fn empty_region_files_are_skipped() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    // a.bed has no regions, so bedtools reports no overlap
+    fixture.bed("a.bed", "", "", "");
+    let output = fixture.run_ok(&[]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "B1"), ("r3", "B2")]));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No read overlaps a.bed, skipped"));
+
+    fixture.bed("b.bed", "", "", "");
+    let output = fixture.run_ok(&[]);
+    assert_eq!(read(&fixture.path("out/sample.reloc.bed")), "");
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("No read overlaps any region in"), "{}", log);
+}
+
+#[test]
+fn annotation_qc_reports_every_file_before_screening() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    // A1 and A3 overlap, as do the two B1 intervals
+    fixture.bed("a.bed", "chr1\t120\t200\tA1\t0\t+\nchr1\t150\t250\tA3\t0\t+\nchr1\t310\t330\tA2\t0\t+\n", A_OVERLAPS, &[R1, R2].concat());
+    fixture.bed("b.bed", "chr1\t90\t125\tB1\t0\t+\nchr1\t100\t130\tB1\t0\t+\nchr2\t1000\t1100\tB2\t0\t+\n", B_OVERLAPS, &[R1, R3].concat());
+    let output = fixture.run_ok(&["--annotation-qc"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    let a = lines.iter().position(|line| line.contains("Annotation QC of a.bed")).expect(&stdout);
+    let b = lines.iter().position(|line| line.contains("Annotation QC of b.bed")).expect(&stdout);
+    // each report is followed by its own overlaps, in file order
+    assert!(a < b, "{}", stdout);
+    assert!(lines[a + 1].contains("A1 and A3 overlap 1 times"), "{}", stdout);
+    assert!(lines[b + 1].contains("B1 overlaps itself 1 times"), "{}", stdout);
+
+    // a file that fails the QC stops the run before any file is screened
+    fs::remove_file(fixture.path("bedtools.log")).unwrap();
+    fixture.write("regions/c.bed", "chr1\tstart\tend\tC1\t0\t+\n");
+    let output = fixture.run(&["--annotation-qc"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("c.bed"), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(fixture.screened().is_empty(), "{:?}", fixture.intersects());
+}
+
+#[test]
+fn timeout_total_kills_running_intersects_and_writes_partial_output() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    fixture.write("data/b.bed.sleep", "60");
+    let start = std::time::Instant::now();
+    let output = fixture.run(&["--timeout-total", "3"]);
+    // the hanging intersect of b.bed is killed at the limit rather than waited for
+    assert!(start.elapsed() < Duration::from_secs(30), "took {:?}", start.elapsed());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(124), "{}", stderr);
+    assert!(stderr.contains("reached the time limit of 3s, the outputs are partial"), "{}", stderr);
+    assert!(!fixture.path("out/sample.reloc.bed").exists());
+    let assigned: Vec<String> = read(&fixture.path("out/sample.partial.reloc.bed")).lines()
+        .map(|line| line.split('\t').nth(6).unwrap().to_string())
+        .collect();
+    assert_eq!(assigned, ["A1", "A2"]);
+
+    // with nothing finished there is no output
+    fixture.write("data/a.bed.sleep", "60");
+    let output = fixture.run(&["--timeout-total", "1"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(124), "{}", stderr);
+    assert!(stderr.contains("reached the time limit of 1s, no BED file finished"), "{}", stderr);
+}
+
+#[test]
+fn timing_table_has_a_row_per_screened_bed_file() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    fixture.bed("c.bed", "chr3\t0\t100\tC1\t0\t+\n", "", "");
+    fixture.bed("d.bed", "chr4\t0\t100\tD1\t0\t+\n", "", "");
+    fixture.write("data/d.bed.sleep", "60");
+    // the rows of the logged table as source and status, sorted since they come slowest first
+    let timings = |output: &Output| -> Vec<(String, String)> {
+        let mut rows: Vec<(String, String)> = String::from_utf8_lossy(&output.stderr).lines()
+            .skip_while(|line| !line.contains("Time per BED file"))
+            .skip_while(|line| !line.starts_with('╞'))
+            .skip(1)
+            .take_while(|line| line.starts_with('│'))
+            .map(|line| {
+                let fields: Vec<&str> = line.trim_matches('│').split('┆').map(str::trim).collect();
+                (fields[0].to_string(), fields[1].to_string())
+            })
+            .collect();
+        rows.sort();
+        rows
+    };
+    let output = fixture.run_ok(&["--per-bed-timeout", "1"]);
+    assert_eq!(timings(&output), pairs(&[("a.bed", "screened"), ("b.bed", "screened"), ("c.bed", "empty"), ("d.bed", "skipped")]));
+
+    // on one thread the files run in order, c.bed and d.bed are never started after b.bed hangs
+    fs::remove_file(fixture.path("data/d.bed.sleep")).unwrap();
+    fixture.write("data/b.bed.sleep", "60");
+    let output = fixture.command()
+        .env("RAYON_NUM_THREADS", "1")
+        .arg("-a").arg(fixture.path("sample.bam"))
+        .arg("-r").arg(fixture.path("regions"))
+        .args(["--timeout-total", "2"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(124), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(timings(&output), pairs(&[("a.bed", "screened"), ("b.bed", "timed out")]));
+}
+
+#[test]
+fn config_file_options_yield_to_the_command_line() {
+    let fixture = Fixture::new();
+    antisense(&fixture);
+    let config = fixture.write("config.toml", "strand = \"ignore\"\nwith-coverage = true\n");
+    fixture.run_ok(&["--config", config.to_str().unwrap()]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r7", "A1"), ("r2", "A2"), ("r3", "B2")]));
+    assert!(strand_flags(&fixture).is_empty(), "{:?}", fixture.intersects());
+    let coverage: Vec<String> = read(&fixture.path("out/sample.reloc.bed")).lines()
+        .map(|line| line.split('\t').nth(9).unwrap().to_string())
+        .collect();
+    assert_eq!(coverage, ["30.0", "40.0", "10.0", "40.0"]);
+
+    fs::remove_file(fixture.path("bedtools.log")).unwrap();
+    fixture.run_ok(&["--config", config.to_str().unwrap(), "--strand", "same"]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r2", "A2"), ("r3", "B2")]));
+    assert_eq!(strand_flags(&fixture), ["-s"]);
+    assert!(read(&fixture.path("out/sample.reloc.bed")).lines().all(|line| line.split('\t').count() == 10));
+}
+
+#[test]
+fn merge_bed_merges_unsorted_intervals_per_name_and_strand() {
+    let fixture = Fixture::new();
+    let input = fixture.write("exons.bed", "chr2\t5\t10\tG1\t0\t+
+chr1\t300\t400\tG1\t0\t+
+chr1\t150\t250\tG1\t0\t+
+chr1\t180\t260\tG1\t0\t-
+chr1\t100\t200\tG1\t0\t+
+chr1\t120\t220\tG2\t0\t+
+chr1\t250\t280\tG1\t0\t+
+chr1\t210\t230\tG1\t0\t-
+");
+    let merged = fixture.path("merged.bed");
+    let output = Command::new(env!("CARGO_BIN_EXE_locreadion"))
+        .arg("merge-bed").arg(&input).arg(&merged)
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    // G1 on + overlaps at 150-200 and touches at 250, G2 and G1 on - stay apart
+    assert_eq!(read(&merged), "chr1\t100\t280\tG1\t0\t+
+chr1\t120\t220\tG2\t0\t+
+chr1\t180\t260\tG1\t0\t-
+chr1\t300\t400\tG1\t0\t+
+chr2\t5\t10\tG1\t0\t+
+");
+}
+
+#[test]
+fn candidates_of_a_read_over_three_regions() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    fixture.bed("c.bed", "chr1\t130\t150\tC1\t0\t+\n",
+        "chr1\t100\t150\tr1\t60\t+\t100\t150\t0,0,0\t1\t50\t0\tchr1\t130\t150\tC1\t0\t+\t20\n", R1);
+    let candidates = fixture.path("candidates.tsv");
+    fixture.run_ok(&["--report-candidates", candidates.to_str().unwrap()]);
+    assert_eq!(read(&candidates), "read\tchr\tregion_0\tregion_1\tregion\tcoverage
+r1\tchr1\t90\t125\tB1\t25.0
+r1\tchr1\t120\t200\tA1\t30.0
+r1\tchr1\t130\t150\tC1\t20.0
+");
+    let r1 = |fixture: &Fixture| fixture.assigned().into_iter().filter(|(read, _)| read == "r1").collect::<Vec<_>>();
+    assert_eq!(r1(&fixture), pairs(&[("r1", "A1")]));
+    fixture.run_ok(&["--no-resolve"]);
+    assert_eq!(r1(&fixture), pairs(&[("r1", "B1"), ("r1", "A1"), ("r1", "C1")]));
+}
+
+#[test]
+fn coord_base_shifts_the_output_and_the_candidates_report() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    let candidates = fixture.path("candidates.tsv");
+    let starts = |fixture: &Fixture| -> Vec<(String, String, String)> {
+        read(&fixture.path("out/sample.reloc.bed")).lines()
+            .map(|line| {
+                let fields: Vec<&str> = line.split('\t').collect();
+                (fields[3].to_string(), fields[1].to_string(), fields[4].to_string())
+            })
+            .collect()
+    };
+    let owned = |expected: &[(&str, &str, &str)]| -> Vec<(String, String, String)> {
+        expected.iter().map(|&(read, align_0, region_0)| (read.to_string(), align_0.to_string(), region_0.to_string())).collect()
+    };
+    fixture.run_ok(&["--coord-base", "0", "--report-candidates", candidates.to_str().unwrap()]);
+    assert_eq!(starts(&fixture), owned(&[("r1", "100", "120"), ("r2", "300", "310"), ("r3", "1000", "1000")]));
+    assert_eq!(read(&candidates), "read\tchr\tregion_0\tregion_1\tregion\tcoverage
+r1\tchr1\t90\t125\tB1\t25.0
+r1\tchr1\t120\t200\tA1\t30.0
+");
+    fixture.run_ok(&["--coord-base", "1", "--report-candidates", candidates.to_str().unwrap()]);
+    assert_eq!(starts(&fixture), owned(&[("r1", "101", "121"), ("r2", "301", "311"), ("r3", "1001", "1001")]));
+    assert_eq!(read(&candidates), "read\tchr\tregion_0\tregion_1\tregion\tcoverage
+r1\tchr1\t91\t125\tB1\t25.0
+r1\tchr1\t121\t200\tA1\t30.0
+");
+}
+
+#[test]
+fn primary_only_drops_secondary_alignments() {
+    let fixture = Fixture::new();
+    // the alignment to B1 is a secondary one (FLAG 256)
+    multi_mapped(&fixture);
+    let summary_file = fixture.path("summary.json");
+    fixture.run_ok(&["--summary", summary_file.to_str().unwrap()]);
+    let disposition = summary(&summary_file);
+    assert_eq!((disposition["unique"], disposition["ambiguous"]), (0, 1));
+    fixture.run_ok(&["--primary-only", "--summary", summary_file.to_str().unwrap()]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1")]));
+    let disposition = summary(&summary_file);
+    assert_eq!((disposition["unique"], disposition["ambiguous"]), (1, 0));
+}
+
+#[test]
+fn annotations_become_named_regions() {
+    let fixture = Fixture::new();
+    let gtf = fixture.write("genes.gtf", "#!genome-build test
+chr1\tsrc\tgene\t101\t500\t.\t+\t.\tgene_id \"G1\"; gene_name \"A\";
+chr1\tsrc\ttranscript\t101\t500\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";
+chr1\tsrc\texon\t101\t200\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\"; exon_id \"E1\";
+chr1\tsrc\texon\t401\t500\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";
+");
+    let gff3 = fixture.write("genes.gff3", "##gff-version 3
+chr1\tsrc\tgene\t101\t500\t.\t-\t.\tID=G1
+chr1\tsrc\tmRNA\t101\t500\t.\t-\t.\tID=T1;Parent=G1
+chr1\tsrc\texon\t101\t200\t.\t-\t.\tID=E1;Parent=T1
+chr1\tsrc\texon\t401\t500\t.\t-\t.\tParent=T1
+");
+    // no read overlaps, so every converted region is written as empty
+    let empty = fixture.path("empty.bed");
+    let regions = |annotation: &Path, feature_type: &str| {
+        let output = fixture.command()
+            .arg("-a").arg(fixture.path("sample.bam"))
+            .arg("-r").arg(annotation)
+            .args(["--feature-type", feature_type, "--empty-regions"]).arg(&empty)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        read(&empty)
+    };
+    // the second exon has no exon_id and is named by its transcript
+    assert_eq!(regions(&gtf, "exon"), "chr1\t100\t200\tE1\t0\t+\nchr1\t400\t500\tT1\t0\t+\n");
+    assert_eq!(regions(&gtf, "gene"), "chr1\t100\t500\tG1\t0\t+\n");
+    assert_eq!(regions(&gtf, "transcript"), "chr1\t100\t500\tT1\t0\t+\n");
+    assert!(fixture.intersects().iter().all(|args| args.contains("/genes.bed ")), "{:?}", fixture.intersects());
+    // GFF3 features are named by ID, else Parent
+    assert_eq!(regions(&gff3, "exon"), "chr1\t100\t200\tE1\t0\t-\nchr1\t400\t500\tT1\t0\t-\n");
+    assert_eq!(regions(&gff3, "gene"), "chr1\t100\t500\tG1\t0\t-\n");
+    assert_eq!(regions(&gff3, "transcript"), "chr1\t100\t500\tT1\t0\t-\n");
+}
+
+#[test]
+fn summary_counts_add_up_to_the_total() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    // r4 covers A3 and B3 by 20 bases each, a tie --tie-break drop leaves unresolved
+    let r4 = "r4\t0\tchr1\t601\t60\t40M\t*\t0\t0\t*\t*\n";
+    fixture.bed("a.bed", "chr1\t120\t200\tA1\t0\t+\nchr1\t310\t330\tA2\t0\t+\nchr1\t600\t620\tA3\t0\t+\n",
+        &[A_OVERLAPS, "chr1\t600\t640\tr4\t60\t+\t600\t640\t0,0,0\t1\t40\t0\tchr1\t600\t620\tA3\t0\t+\t20\n"].concat(),
+        &[R1, R2, r4].concat());
+    fixture.bed("b.bed", "chr1\t90\t125\tB1\t0\t+\nchr2\t1000\t1100\tB2\t0\t+\nchr1\t620\t640\tB3\t0\t+\n",
+        &[B_OVERLAPS, "chr1\t600\t640\tr4\t60\t+\t600\t640\t0,0,0\t1\t40\t0\tchr1\t620\t640\tB3\t0\t+\t20\n"].concat(),
+        &[R1, R3, r4].concat());
+    let summary_file = fixture.path("summary.json");
+    // r1 is resolved, r2 with 10 bases over A2 falls below the threshold and r3 is unique
+    fixture.run_ok(&["--tie-break", "drop", "--min-coverage", "15", "--summary", summary_file.to_str().unwrap()]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r3", "B2")]));
+    let disposition = summary(&summary_file);
+    for key in ["unique", "resolved", "unresolved", "dropped_by_threshold"] {
+        assert_eq!(disposition[key], 1, "{}", key);
+    }
+    assert_eq!(disposition["ambiguous"], 2);
+    let parts: usize = disposition.iter().filter(|(key, _)| !["total_reads", "ambiguous"].contains(&key.as_str())).map(|(_, n)| n).sum();
+    assert_eq!(parts, disposition["total_reads"]);
+    assert_eq!(disposition["total_reads"], 4);
+}
+
+#[test]
+fn missing_bedtools_is_named() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    // the fixture's command pins the stand-in bedtools already
+    let command = || {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_locreadion"));
+        command
+            .arg("-a").arg(fixture.path("sample.bam"))
+            .arg("-r").arg(fixture.path("regions"))
+            .arg("-o").arg(fixture.path("out"))
+            .arg("--samtools").arg(fixture.path("bin/samtools"))
+            .env_remove("BEDTOOLS")
+            .env_remove("RUST_LOG");
+        command
+    };
+    for output in [
+        command().args(["--bedtools", "/nonexistent"]).output().unwrap(),
+        command().env("BEDTOOLS", "/nonexistent").output().unwrap(),
+    ] {
+        assert_eq!(output.status.code(), Some(1));
+        let log = String::from_utf8_lossy(&output.stderr);
+        assert!(log.contains("The bedtools executable /nonexistent do not exist."), "{}", log);
+    }
+    assert!(fixture.intersects().is_empty());
+}
+
+#[test]
+fn output_order_does_not_depend_on_the_inputs() {
+    // r8 and r9 align to the same interval on opposite strands, Z1 and Y1 share their coordinates
+    let z1 = ("chr1\t100\t150\tZ1\t0\t+\n", "chr1\t100\t150\tr8\t60\t+\t100\t150\t0,0,0\t1\t50\t0\tchr1\t100\t150\tZ1\t0\t+\t50\n", "r8\t0\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*\n");
+    let y1 = ("chr1\t100\t150\tY1\t0\t-\n", "chr1\t100\t150\tr9\t60\t-\t100\t150\t0,0,0\t1\t50\t0\tchr1\t100\t150\tY1\t0\t-\t50\n", "r9\t16\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*\n");
+    let reversed = |tsv: &str| tsv.lines().rev().map(|line| format!("{}\n", line)).collect::<String>();
+    let forward = Fixture::new();
+    overlapping(&forward);
+    forward.bed("c.bed", &[z1.0, y1.0].concat(), &[z1.1, y1.1].concat(), &[z1.2, y1.2].concat());
+    forward.run_ok(&[]);
+    // r1 shares the alignment too, its region starts later; the region names break the tie before the read names
+    assert_eq!(forward.assigned(), pairs(&[("r9", "Y1"), ("r8", "Z1"), ("r1", "A1"), ("r2", "A2"), ("r3", "B2")]));
+
+    // the BED files swapped, and every file and SAM listing upside down
+    let backward = Fixture::new();
+    backward.bed("a.bed", &[y1.0, z1.0].concat(), &[y1.1, z1.1].concat(), &[y1.2, z1.2].concat());
+    backward.bed("b.bed", &reversed("chr1\t90\t125\tB1\t0\t+\nchr2\t1000\t1100\tB2\t0\t+\n"), &reversed(B_OVERLAPS), &[R3, R1].concat());
+    backward.bed("c.bed", &reversed("chr1\t120\t200\tA1\t0\t+\nchr1\t310\t330\tA2\t0\t+\n"), &reversed(A_OVERLAPS), &[R2, R1].concat());
+    backward.run_ok(&[]);
+    assert_eq!(read(&backward.path("out/sample.reloc.bed")), read(&forward.path("out/sample.reloc.bed")));
+}
+
+#[test]
+fn keep_temp_matches_the_in_memory_run() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    fixture.run_ok(&[]);
+    let in_memory = read(&fixture.path("out/sample.reloc.bed"));
+    let keep_dir = fixture.path("intersects");
+    fs::create_dir(&keep_dir).unwrap();
+    fixture.run_ok(&["--keep-temp", keep_dir.to_str().unwrap()]);
+    assert_eq!(read(&fixture.path("out/sample.reloc.bed")), in_memory);
+    for bed in ["a", "b"] {
+        assert_eq!(read(&keep_dir.join(format!("{}.intersect.bed", bed))), read(&fixture.path(&format!("data/{}.bed.bed", bed))));
+        assert_eq!(read(&keep_dir.join(format!("{}.intersect.sam", bed))), read(&fixture.path(&format!("data/{}.bed.sam", bed))));
+    }
+}
+
+#[test]
+fn read_key_separates_mates() {
+    let fixture = Fixture::new();
+    // the first mate of r1 (FLAG 65) overlaps A1, the second (FLAG 129) B1; r2/1 and r2/2 are named apart
+    fixture.bed("a.bed", "chr1\t100\t150\tA1\t0\t+\n",
+        "chr1\t100\t150\tr1\t60\t+\t100\t150\t0,0,0\t1\t50\t0\tchr1\t100\t150\tA1\t0\t+\t50
+chr1\t100\t140\tr2/1\t60\t+\t100\t140\t0,0,0\t1\t40\t0\tchr1\t100\t150\tA1\t0\t+\t40\n",
+        "r1\t65\tchr1\t101\t60\t50M\t=\t101\t0\t*\t*\nr2/1\t65\tchr1\t101\t60\t40M\t*\t0\t0\t*\t*\n");
+    fixture.bed("b.bed", "chr2\t100\t150\tB1\t0\t+\n",
+        "chr2\t100\t150\tr1\t60\t+\t100\t150\t0,0,0\t1\t50\t0\tchr2\t100\t150\tB1\t0\t+\t50
+chr2\t100\t140\tr2/2\t60\t+\t100\t140\t0,0,0\t1\t40\t0\tchr2\t100\t150\tB1\t0\t+\t40\n",
+        "r1\t129\tchr2\t101\t60\t50M\t*\t0\t0\t*\t*\nr2/2\t129\tchr2\t101\t60\t40M\t*\t0\t0\t*\t*\n");
+    let summary_file = fixture.path("summary.json");
+    fixture.run_ok(&["--summary", summary_file.to_str().unwrap()]);
+    assert_eq!(fixture.assigned(), pairs(&[("r2/1", "A1"), ("r1", "A1"), ("r2/2", "B1")]));
+    let disposition = summary(&summary_file);
+    assert_eq!((disposition["total_reads"], disposition["unique"], disposition["ambiguous"]), (3, 2, 1));
+    for read_key in ["mate", "alignment"] {
+        fixture.run_ok(&["--read-key", read_key, "--summary", summary_file.to_str().unwrap()]);
+        assert_eq!(fixture.assigned(), pairs(&[("r2/1", "A1"), ("r1", "A1"), ("r2/2", "B1"), ("r1", "B1")]), "--read-key {}", read_key);
+        let disposition = summary(&summary_file);
+        assert_eq!((disposition["total_reads"], disposition["unique"], disposition["ambiguous"]), (4, 4, 0), "--read-key {}", read_key);
+    }
+}
+
+#[test]
+fn paired_sums_the_coverage_of_both_mates() {
+    let fixture = Fixture::new();
+    // r1/1 covers A1 by 30 and B1 by 50, r1/2 only A1 by 30; r2/1 covers A2 by 45 and B2 by 10, r2/2 only B2 by 20
+    let line = |read: &str, start: i64, end: i64, region: (i64, i64, &str)| format!(
+        "chr1\t{start}\t{end}\t{read}\t60\t+\t{start}\t{end}\t0,0,0\t1\t{len}\t0\tchr1\t{}\t{}\t{}\t0\t+\t{overlap}\n",
+        region.0, region.1, region.2, len = end - start, overlap = end.min(region.1) - start.max(region.0));
+    let r1_1 = "r1/1\t65\tchr1\t101\t60\t50M\t=\t301\t0\t*\t*\n";
+    let r1_2 = "r1/2\t129\tchr1\t301\t60\t30M\t=\t101\t0\t*\t*\n";
+    let r2_1 = "r2/1\t65\tchr1\t1001\t60\t45M\t=\t1201\t0\t*\t*\n";
+    let r2_2 = "r2/2\t129\tchr1\t1201\t60\t20M\t=\t1001\t0\t*\t*\n";
+    fixture.bed("a.bed", "chr1\t120\t200\tA1\t0\t+\nchr1\t300\t400\tA1\t0\t+\nchr1\t1000\t1100\tA2\t0\t+\n",
+        &[line("r1/1", 100, 150, (120, 200, "A1")), line("r1/2", 300, 330, (300, 400, "A1")), line("r2/1", 1000, 1045, (1000, 1100, "A2"))].concat(),
+        &[r1_1, r1_2, r2_1].concat());
+    fixture.bed("b.bed", "chr1\t100\t150\tB1\t0\t+\nchr1\t1035\t1100\tB2\t0\t+\nchr1\t1200\t1300\tB2\t0\t+\n",
+        &[line("r1/1", 100, 150, (100, 150, "B1")), line("r2/1", 1000, 1045, (1035, 1100, "B2")), line("r2/2", 1200, 1220, (1200, 1300, "B2"))].concat(),
+        &[r1_1, r2_1, r2_2].concat());
+    fixture.run_ok(&[]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1/1", "B1"), ("r1/2", "A1"), ("r2/1", "A2"), ("r2/2", "B2")]));
+    // A1 wins r1 by 30 + 30 against 50 + 0, A2 wins r2 by 45 against 10 + 20 and r2/2 does not reach it
+    fixture.run_ok(&["--paired"]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1/1", "A1"), ("r1/2", "A1"), ("r2/1", "A2")]));
+}
+
+#[test]
+fn dry_run_checks_and_prints_the_plan() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    fs::remove_dir(fixture.path("out")).unwrap();
+    let output = fixture.run(&["--dry-run"]);
+    assert_eq!(output.status.code(), Some(1));
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("Output directory do not exist."), "{}", log);
+    assert!(fixture.intersects().is_empty(), "{:?}", fixture.intersects());
+
+    fs::create_dir(fixture.path("out")).unwrap();
+    let output = fixture.run_ok(&["--dry-run"]);
+    let dir = fixture.dir.path().canonicalize().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("Dry run, 1 samples
+sample
+Alignment: {dir}/sample.bam
+Regions: 2 BED files in {dir}/regions: a.bed, b.bed
+Output: {dir}/out/sample.reloc.bed
+", dir = dir.display()));
+    assert!(fixture.intersects().is_empty(), "{:?}", fixture.intersects());
+    assert!(!fixture.path("out/sample.reloc.bed").exists());
+}
+
+#[test]
+fn compress_writes_the_output_as_bgzf() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    fixture.run_ok(&[]);
+    let plain = read(&fixture.path("out/sample.reloc.bed"));
+    fixture.run_ok(&["--compress"]);
+    let compressed = fs::read(fixture.path("out/sample.reloc.bed.gz")).unwrap();
+    // the empty block bgzip ends a file with
+    assert!(compressed.ends_with(&[
+        0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, b'B', b'C', 0x02, 0,
+        0x1b, 0, 0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ]));
+    let mut decompressed = String::new();
+    MultiGzDecoder::new(&compressed[..]).read_to_string(&mut decompressed).unwrap();
+    assert_eq!(decompressed, plain);
+}
+
+#[test]
+fn bed12_regions_are_named_by_column_4() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    // bedtools appends all twelve region fields before the overlap
+    let b2 = "chr2\t1000\t1100\tB2\t0\t+\t1000\t1100\t255,0,0\t2\t30,40\t0,60";
+    fixture.bed("b.bed", &format!("chr1\t90\t125\tB1\t0\t+\t90\t125\t255,0,0\t1\t35\t0\n{}\n", b2),
+        &format!("chr1\t100\t150\tr1\t60\t+\t100\t150\t0,0,0\t1\t50\t0\tchr1\t90\t125\tB1\t0\t+\t90\t125\t255,0,0\t1\t35\t0\t25
+chr2\t1000\t1040\tr3\t60\t+\t1000\t1040\t0,0,0\t1\t40\t0\t{}\t30\n", b2),
+        &[R1, R3].concat());
+    fixture.run_ok(&[]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r2", "A2"), ("r3", "B2")]));
+    let b2_row = read(&fixture.path("out/sample.reloc.bed")).lines().last().unwrap().to_string();
+    assert_eq!(b2_row, "chr2\t1000\t1040\tr3\t1000\t1100\tB2\t40M\t+");
+}
+
+#[test]
+fn min_read_length_drops_short_alignments() {
+    let fixture = Fixture::new();
+    // r1 aligns by 50 bases to A1 and by 20 to B1, r2 by 20 to B1 only; r3 is spliced, 25 bases over 125
+    fixture.bed("a.bed", "chr1\t100\t200\tA1\t0\t+\nchr1\t300\t450\tA2\t0\t+\n",
+        "chr1\t100\t150\tr1\t60\t+\t100\t150\t0,0,0\t1\t50\t0\tchr1\t100\t200\tA1\t0\t+\t50
+chr1\t300\t425\tr3\t60\t+\t300\t425\t0,0,0\t2\t10,15\t0,110\tchr1\t300\t450\tA2\t0\t+\t25\n",
+        "r1\t0\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*\nr3\t0\tchr1\t301\t60\t10M100N15M\t*\t0\t0\t*\t*\n");
+    fixture.bed("b.bed", "chr2\t100\t200\tB1\t0\t+\n",
+        "chr2\t100\t120\tr1\t60\t+\t100\t120\t0,0,0\t1\t20\t0\tchr2\t100\t200\tB1\t0\t+\t20
+chr2\t150\t170\tr2\t60\t+\t150\t170\t0,0,0\t1\t20\t0\tchr2\t100\t200\tB1\t0\t+\t20\n",
+        "r1\t256\tchr2\t101\t60\t20M\t*\t0\t0\t*\t*\nr2\t0\tchr2\t151\t60\t20M\t*\t0\t0\t*\t*\n");
+    let summary_file = fixture.path("summary.json");
+    fixture.run_ok(&["--summary", summary_file.to_str().unwrap()]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r3", "A2"), ("r2", "B1")]));
+    assert_eq!(summary(&summary_file)["ambiguous"], 1);
+
+    fixture.run_ok(&["--min-read-length", "30", "--summary", summary_file.to_str().unwrap()]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1")]));
+    let disposition = summary(&summary_file);
+    assert_eq!((disposition["unique"], disposition["ambiguous"]), (1, 0));
+
+    // the intron counts towards the length of the whole span
+    fixture.run_ok(&["--min-read-length", "30", "--no-split"]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r3", "A2")]));
+}
+
+#[test]
+fn parallel_join_matches_the_serial_one() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    fixture.bed("c.bed", "chr1\t130\t150\tC1\t0\t+\n",
+        "chr1\t100\t150\tr1\t60\t+\t100\t150\t0,0,0\t1\t50\t0\tchr1\t130\t150\tC1\t0\t+\t20\n", R1);
+    fixture.run_ok(&["--join-strategy", "serial", "--with-coverage"]);
+    let serial = read(&fixture.path("out/sample.reloc.bed"));
+    assert_eq!(serial.lines().count(), 3);
+    fixture.run_ok(&["--join-strategy", "parallel", "--with-coverage"]);
+    assert_eq!(read(&fixture.path("out/sample.reloc.bed")), serial);
+}
+
+#[test]
+fn cache_dir_skips_unchanged_intersects() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    let cache = fixture.path("cache");
+    let cached = ["--cache-dir", cache.to_str().unwrap()];
+    let expected = pairs(&[("r1", "A1"), ("r2", "A2"), ("r3", "B2")]);
+    // the BED files intersected since the last call
+    let screened = || {
+        let files = fixture.screened();
+        fs::remove_file(fixture.path("bedtools.log")).ok();
+        files
+    };
+    fixture.run_ok(&cached);
+    assert_eq!(screened(), ["a.bed", "b.bed"]);
+    let output = fixture.run_ok(&cached);
+    assert!(screened().is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Reusing the cached intersect output"));
+    assert_eq!(fixture.assigned(), expected);
+
+    // a newer modification time is a new key
+    fs::File::options().write(true).open(fixture.path("regions/a.bed")).unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+    fixture.run_ok(&cached);
+    assert_eq!(screened(), ["a.bed"]);
+    fixture.run_ok(&[&cached[..], &["--strand", "ignore"]].concat());
+    assert_eq!(screened(), ["a.bed", "b.bed"]);
+    fixture.run_ok(&[&cached[..], &["--strand", "ignore"]].concat());
+    assert!(screened().is_empty());
+
+    fixture.run_ok(&[&cached[..], &["--no-cache"]].concat());
+    assert_eq!(screened(), ["a.bed", "b.bed"]);
+    assert_eq!(fixture.assigned(), expected);
+}
+
+#[test]
+fn umi_tag_collapses_duplicates() {
+    let fixture = Fixture::new();
+    // every read starts at 100 in A1; the UB tag follows NH, and r3 and r4 have no tags
+    let reads = ["r3", "r1", "r2", "r4", "r5"];
+    let tags = ["", "\tNH:i:1\tUB:Z:AAAA", "\tNH:i:1\tUB:Z:AAAA", "", "\tNH:i:1\tUB:Z:CCCC"];
+    let overlaps: String = reads.iter()
+        .map(|read| format!("chr1\t100\t150\t{}\t60\t+\t100\t150\t0,0,0\t1\t50\t0\tchr1\t100\t200\tA1\t0\t+\t50\n", read))
+        .collect();
+    let sam: String = reads.iter().zip(tags)
+        .map(|(read, tag)| format!("{}\t0\tchr1\t101\t60\t50M\t*\t0\t0\t*\t*{}\n", read, tag))
+        .collect();
+    fixture.bed("a.bed", "chr1\t100\t200\tA1\t0\t+\n", &overlaps, &sam);
+    fixture.run_ok(&[]);
+    assert_eq!(fixture.assigned().len(), 5);
+    fixture.run_ok(&["--umi-tag", "UB"]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r3", "A1"), ("r4", "A1"), ("r5", "A1")]));
+}
+
+#[test]
+fn unassigned_lists_reads_without_an_overlap() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    // r4 has a second alignment, also outside every region
+    fixture.write("data/all.sam", &[R1, R2, R3, R4, R5, "r4\t256\tchr4\t501\t0\t30M\t*\t0\t0\t*\t*\n"].concat());
+    let unassigned = fixture.path("unassigned.bed");
+    // r2 covers A2 by 10 bases only and is dropped after the overlap
+    fixture.run_ok(&["--min-coverage", "20", "--unassigned", unassigned.to_str().unwrap()]);
+    assert_eq!(fixture.assigned(), pairs(&[("r1", "A1"), ("r3", "B2")]));
+    assert_eq!(read(&unassigned), "chr3\t10\t40\tr4\t30M\t+\nchr3\t20\t50\tr5\t30M\t-\n");
+}
+
+#[test]
+fn samtools_without_records_fails_the_bed_file() {
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    // a samtools that cannot read what bedtools -ubam writes, and prints no record
+    fixture.write("bin/samtools", "#!/bin/sh\n[ \"$1\" = --version ] && { echo \"samtools 1.17\"; exit 0; }\ncat > /dev/null\nexit 0\n");
+    // only b.bed has overlaps and gets to samtools
+    fixture.bed("a.bed", "chr1\t120\t200\tA1\t0\t+\n", "", "");
+    let output = fixture.run(&[]);
+    assert_eq!(output.status.code(), Some(1));
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("samtools view failed while screening b.bed: no SAM records although bedtools reported overlaps"), "{}", log);
+    assert!(!fixture.path("out/sample.reloc.bed").exists());
+}
+
+// the overlapping fixture written as a real BAM, with stored qualities above the Phred+33 range
+#[cfg(feature = "native")]
+#[test]
+fn native_engine_matches_the_bedtools_path() {
+    use rust_htslib::bam::{self, Header, HeaderView, header::HeaderRecord};
+
+    let fixture = Fixture::new();
+    overlapping(&fixture);
+    fixture.run_ok(&[]);
+    let bedtools = read(&fixture.path("out/sample.reloc.bed"));
+
+    let mut header = Header::new();
+    for chr in ["chr1", "chr2"] {
+        header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", chr).push_tag(b"LN", 10000));
+    }
+    let view = HeaderView::from_header(&header);
+    let mut writer = bam::Writer::from_path(fixture.path("sample.bam"), &header, bam::Format::Bam).unwrap();
+    for (line, length) in [(R1, 50), (R2, 50), (R3, 40)] {
+        let fields: Vec<&str> = line.trim_end().split('\t').collect();
+        let sam = [&fields[..9], &["A".repeat(length).as_str(), "*"]].concat().join("\t");
+        let mut record = bam::Record::from_sam(&view, sam.as_bytes()).unwrap();
+        let (qname, cigar) = (record.qname().to_vec(), record.cigar().take());
+        record.set(&qname, Some(&cigar), "A".repeat(length).as_bytes(), &vec![230; length]);
+        writer.write(&record).unwrap();
+    }
+    drop(writer);
+
+    fixture.run_ok(&["--engine", "native"]);
+    assert_eq!(read(&fixture.path("out/sample.reloc.bed")), bedtools);
+    fixture.run_ok(&["--engine", "native", "--qual-weight"]);
+    assert_eq!(read(&fixture.path("out/sample.reloc.bed")), bedtools);
+}